@@ -0,0 +1,57 @@
+#!/bin/sh
+# Runs the Kerberos tests of the `gssapi` feature against a throwaway realm (TIBERIUS.TEST),
+# requires the MIT Kerberos KDC and tools (e.g. the Debian packages krb5-kdc, krb5-admin-server and krb5-user)
+set -e
+
+DIR=$(mktemp -d)
+PORT=${KDC_PORT:-60088}
+REALM=TIBERIUS.TEST
+SPN=MSSQLSvc/sql.tiberius.test:1433
+
+cat > "$DIR/krb5.conf" <<CONF
+[libdefaults]
+    default_realm = $REALM
+    dns_lookup_kdc = false
+    dns_lookup_realm = false
+    rdns = false
+[realms]
+    $REALM = {
+        kdc = 127.0.0.1:$PORT
+    }
+CONF
+cat > "$DIR/kdc.conf" <<CONF
+[kdcdefaults]
+    kdc_ports = $PORT
+    kdc_tcp_ports = $PORT
+[realms]
+    $REALM = {
+        database_name = $DIR/principal
+        key_stash_file = $DIR/stash
+        acl_file = $DIR/kadm5.acl
+    }
+[logging]
+    kdc = FILE:$DIR/kdc.log
+CONF
+
+export KRB5_CONFIG="$DIR/krb5.conf"
+export KRB5_KDC_PROFILE="$DIR/kdc.conf"
+# the service side of the tests accepts the same authenticator more than once
+export KRB5RCACHETYPE=none
+
+kdb5_util create -r "$REALM" -s -P master-password > /dev/null
+kadmin.local -q "addprinc -pw password user" > /dev/null
+kadmin.local -q "addprinc -randkey $SPN" > /dev/null
+kadmin.local -q "ktadd -k $DIR/sql.keytab $SPN" > /dev/null
+
+krb5kdc -n -P "$DIR/kdc.pid" &
+KDC=$!
+trap 'kill $KDC; rm -rf "$DIR"' EXIT
+sleep 1
+
+echo password | kinit -c "FILE:$DIR/krb5cc" user > /dev/null
+
+cd "$(dirname "$0")/tiberius"
+TIBERIUS_TEST_KRB5_CCACHE="FILE:$DIR/krb5cc" \
+TIBERIUS_TEST_KRB5_SPN="$SPN" \
+TIBERIUS_TEST_KRB5_KEYTAB="FILE:$DIR/sql.keytab" \
+    cargo test --features gssapi --lib gssapi -- --include-ignored
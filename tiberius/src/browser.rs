@@ -0,0 +1,446 @@
+//! SQL Server Resolution Protocol (SSRP) [MS-SQLR] used to query the SQL Server Browser
+//! for the endpoints of named instances
+//!
+//! [MS-SQLR] https://msdn.microsoft.com/en-us/library/cc219703.aspx
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use futures::{future, Async, Future, Poll};
+use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+use {ConnectTarget, Error, Result};
+
+/// The port the SQL Server Browser listens on
+pub const BROWSER_PORT: u16 = 1434;
+
+/// [2.2.1] request the information of all instances on all servers of the network
+const CLNT_BCAST_EX: u8 = 0x02;
+/// [2.2.2] request the information of all instances on a single server
+const CLNT_UCAST_EX: u8 = 0x03;
+/// [2.2.4] request the information of one instance on a single server
+const CLNT_UCAST_INST: u8 = 0x04;
+/// [2.2.5] the header byte of every server response
+const SVR_RESP: u8 = 0x05;
+
+/// The information about an instance as announced by the SQL Server Browser [2.2.5]
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceInfo {
+    /// The name of the server (machine) hosting the instance
+    pub server_name: String,
+    /// The name of the instance (`MSSQLSERVER` for the default instance)
+    pub instance_name: String,
+    /// Whether the instance is part of a failover cluster
+    pub clustered: bool,
+    /// The version of the instance (e.g. `14.0.1000.169`)
+    pub version: String,
+    /// The TCP port, if the instance listens on TCP
+    pub tcp_port: Option<u16>,
+    /// The named pipe, if the instance listens on a named pipe
+    pub named_pipe: Option<String>,
+}
+
+/// Parse a SVR_RESP message, which consists of the header and RESP_DATA
+pub fn parse_response(buf: &[u8]) -> Result<Vec<InstanceInfo>> {
+    if buf.len() < 3 || buf[0] != SVR_RESP {
+        return Err(Error::Protocol("ssrp: invalid response header".into()));
+    }
+    let len = LittleEndian::read_u16(&buf[1..3]) as usize;
+    if buf.len() < 3 + len {
+        return Err(Error::Protocol("ssrp: truncated response".into()));
+    }
+    parse_resp_data(str::from_utf8(&buf[3..3 + len])?)
+}
+
+/// Parse RESP_DATA: a list of `;;`-terminated records, each consisting of `key;value` pairs
+fn parse_resp_data(data: &str) -> Result<Vec<InstanceInfo>> {
+    let mut ret = vec![];
+    for record in data.split(";;").filter(|x| !x.is_empty()) {
+        let mut info = InstanceInfo {
+            server_name: String::new(),
+            instance_name: String::new(),
+            clustered: false,
+            version: String::new(),
+            tcp_port: None,
+            named_pipe: None,
+        };
+        let mut parts = record.split(';');
+        while let Some(key) = parts.next() {
+            let value = parts.next().ok_or_else(|| {
+                Error::Protocol(format!("ssrp: missing value for {:?}", key).into())
+            })?;
+            match key.to_lowercase().as_str() {
+                "servername" => info.server_name = value.to_owned(),
+                "instancename" => info.instance_name = value.to_owned(),
+                "isclustered" => info.clustered = value.eq_ignore_ascii_case("yes"),
+                "version" => info.version = value.to_owned(),
+                "tcp" => info.tcp_port = Some(value.parse()?),
+                "np" => info.named_pipe = Some(value.to_owned()),
+                // other protocols (via, rpc, spx, adsp, bv) are of no use to us
+                _ => (),
+            }
+        }
+        if info.instance_name.is_empty() {
+            return Err(Error::Protocol("ssrp: record without instance name".into()));
+        }
+        ret.push(info);
+    }
+    Ok(ret)
+}
+
+/// Bind an UDP socket of the same address family as the given target
+fn bind_for(addr: &SocketAddr) -> Result<UdpSocket> {
+    let local_bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    Ok(UdpSocket::bind(&local_bind)?)
+}
+
+/// A future resolving the endpoint information of a named instance (CLNT_UCAST_INST)
+///
+/// The request is resent `retransmits` times within the `timeout`, since UDP datagrams might get lost.
+/// If no answer arrived in time, this fails with `Error::BrowserTimeout`.
+/// Without a timeout, the request is sent once and the answer is awaited forever.
+#[must_use = "futures do nothing unless polled"]
+pub struct ResolveInstance {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    instance_name: String,
+    request: Vec<u8>,
+    /// whether the request has to be (re)sent
+    send_pending: bool,
+    retransmits_left: u32,
+    interval: Duration,
+    retransmit: Option<Delay>,
+    deadline: Option<Delay>,
+    buf: Vec<u8>,
+}
+
+impl ResolveInstance {
+    pub fn new(
+        addr: SocketAddr,
+        instance_name: &str,
+        timeout: Option<Duration>,
+        retransmits: u32,
+    ) -> Result<ResolveInstance> {
+        let now = Instant::now();
+        let interval = timeout.map(|x| x / retransmits.saturating_add(1)).unwrap_or_default();
+
+        Ok(ResolveInstance {
+            socket: bind_for(&addr)?,
+            addr,
+            instance_name: instance_name.to_owned(),
+            request: [&[CLNT_UCAST_INST], instance_name.as_bytes(), &[0]].concat(),
+            send_pending: true,
+            retransmits_left: retransmits,
+            interval,
+            retransmit: timeout
+                .filter(|_| retransmits > 0)
+                .map(|_| Delay::new(now + interval)),
+            deadline: timeout.map(|x| Delay::new(now + x)),
+            buf: vec![0u8; 4096],
+        })
+    }
+}
+
+impl Future for ResolveInstance {
+    type Item = InstanceInfo;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<InstanceInfo, Error> {
+        loop {
+            if self.send_pending {
+                try_ready!(self.socket.poll_send_to(&self.request, &self.addr));
+                self.send_pending = false;
+            }
+
+            if let Async::Ready((len, _)) = self.socket.poll_recv_from(&mut self.buf)? {
+                let instance_name = &self.instance_name;
+                return parse_response(&self.buf[..len])?
+                    .into_iter()
+                    .find(|x| x.instance_name.eq_ignore_ascii_case(instance_name))
+                    .map(Async::Ready)
+                    .ok_or_else(|| Error::InstanceNotFound(instance_name.clone()));
+            }
+
+            // the browser does not answer at all for instances it does not know,
+            // so this cannot be told apart from an unreachable browser
+            if let Some(ref mut deadline) = self.deadline {
+                if deadline.poll()?.is_ready() {
+                    return Err(Error::BrowserTimeout(self.instance_name.clone()));
+                }
+            }
+
+            let resend = match self.retransmit {
+                Some(ref mut retransmit) => retransmit.poll()?.is_ready(),
+                None => false,
+            };
+            if !resend {
+                return Ok(Async::NotReady);
+            }
+            self.retransmits_left -= 1;
+            self.retransmit = if self.retransmits_left > 0 {
+                Some(Delay::new(Instant::now() + self.interval))
+            } else {
+                None
+            };
+            self.send_pending = true;
+        }
+    }
+}
+
+/// Discover the instances of a single host (`CLNT_UCAST_EX`), usually listening on `BROWSER_PORT`.
+///
+/// The future completes once the host answered and fails with `Error::BrowserTimeout`
+/// if it did not answer within `timeout`, which requires the executor to provide a timer.
+pub fn discover_instances(host: &str, port: u16, timeout: Duration) -> DiscoverInstances {
+    DiscoverInstances::new(ConnectTarget::resolve(host, port), host, false, timeout)
+}
+
+/// Discover the instances of all hosts in the network (`CLNT_BCAST_EX`), using the limited broadcast
+/// address `255.255.255.255` or a subnet-directed broadcast address.
+///
+/// All answers received until `timeout` elapsed are collected, which requires the executor to provide a timer.
+pub fn broadcast_instances(broadcast: IpAddr, port: u16, timeout: Duration) -> DiscoverInstances {
+    let target = future::ok(vec![SocketAddr::new(broadcast, port)]);
+    DiscoverInstances::new(Box::new(target), &broadcast.to_string(), true, timeout)
+}
+
+/// A future collecting the instances announced in answer to `CLNT_UCAST_EX` or `CLNT_BCAST_EX`
+#[must_use = "futures do nothing unless polled"]
+pub struct DiscoverInstances {
+    resolve: Box<Future<Item = Vec<SocketAddr>, Error = Error> + Sync + Send>,
+    host: String,
+    broadcast: bool,
+    target: Option<(UdpSocket, SocketAddr)>,
+    send_pending: bool,
+    deadline: Delay,
+    buf: Vec<u8>,
+    instances: Vec<InstanceInfo>,
+}
+
+impl DiscoverInstances {
+    fn new(
+        resolve: Box<Future<Item = Vec<SocketAddr>, Error = Error> + Sync + Send>,
+        host: &str,
+        broadcast: bool,
+        timeout: Duration,
+    ) -> DiscoverInstances {
+        DiscoverInstances {
+            resolve,
+            host: host.to_owned(),
+            broadcast,
+            target: None,
+            send_pending: true,
+            deadline: Delay::new(Instant::now() + timeout),
+            buf: vec![0u8; 65535],
+            instances: vec![],
+        }
+    }
+
+    /// Wait for the host to resolve and bind a socket to send the request with
+    fn poll_target(&mut self) -> Poll<(), Error> {
+        if self.target.is_none() {
+            let addr = try_ready!(self.resolve.poll())[0];
+            let socket = bind_for(&addr)?;
+            if self.broadcast {
+                socket.set_broadcast(true)?;
+            }
+            self.target = Some((socket, addr));
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Future for DiscoverInstances {
+    type Item = Vec<InstanceInfo>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Vec<InstanceInfo>, Error> {
+        if let Async::Ready(()) = self.poll_target()? {
+            let (socket, addr) = match self.target {
+                Some((ref mut socket, ref addr)) => (socket, addr),
+                None => unreachable!(),
+            };
+
+            if self.send_pending {
+                let request = if self.broadcast { CLNT_BCAST_EX } else { CLNT_UCAST_EX };
+                try_ready!(socket.poll_send_to(&[request], addr));
+                self.send_pending = false;
+            }
+
+            while let Async::Ready((len, _)) = socket.poll_recv_from(&mut self.buf)? {
+                // anyone may answer a broadcast, so simply skip answers we do not understand
+                if let Ok(instances) = parse_response(&self.buf[..len]) {
+                    for instance in instances {
+                        if !self.instances.contains(&instance) {
+                            self.instances.push(instance);
+                        }
+                    }
+                    // a single host answers exactly once
+                    if !self.broadcast {
+                        return Ok(Async::Ready(mem::take(&mut self.instances)));
+                    }
+                }
+            }
+        }
+
+        try_ready!(self.deadline.poll());
+        if !self.broadcast {
+            return Err(Error::BrowserTimeout(self.host.clone()));
+        }
+        Ok(Async::Ready(mem::take(&mut self.instances)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+    use super::{broadcast_instances, discover_instances, parse_resp_data, parse_response, InstanceInfo, ResolveInstance};
+    use Error;
+
+    const RESP_DATA: &str = "ServerName;SQLHOST;InstanceName;SQLEXPRESS;IsClustered;No;\
+        Version;14.0.1000.169;tcp;49172;np;\\\\SQLHOST\\pipe\\MSSQL$SQLEXPRESS\\sql\\query;;\
+        ServerName;SQLHOST;InstanceName;CLUSTERED;IsClustered;Yes;Version;13.0.1601.5;np;\\\\SQLHOST\\pipe\\sql\\query;;";
+
+    fn response(data: &str) -> Vec<u8> {
+        let len = data.len() as u16;
+        [&[0x05, len as u8, (len >> 8) as u8], data.as_bytes()].concat()
+    }
+
+    #[test]
+    fn parse_instances() {
+        let instances = parse_resp_data(RESP_DATA).unwrap();
+        assert_eq!(
+            instances,
+            vec![
+                InstanceInfo {
+                    server_name: "SQLHOST".to_owned(),
+                    instance_name: "SQLEXPRESS".to_owned(),
+                    clustered: false,
+                    version: "14.0.1000.169".to_owned(),
+                    tcp_port: Some(49172),
+                    named_pipe: Some("\\\\SQLHOST\\pipe\\MSSQL$SQLEXPRESS\\sql\\query".to_owned()),
+                },
+                InstanceInfo {
+                    server_name: "SQLHOST".to_owned(),
+                    instance_name: "CLUSTERED".to_owned(),
+                    clustered: true,
+                    version: "13.0.1601.5".to_owned(),
+                    tcp_port: None,
+                    named_pipe: Some("\\\\SQLHOST\\pipe\\sql\\query".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_malformed_response() {
+        assert!(parse_response(&[0x05, 0xff, 0x00]).is_err());
+        assert!(parse_response(&response("ServerName;SQLHOST;;")).is_err());
+        assert!(parse_response(&response("InstanceName;X;tcp;notaport;;")).is_err());
+    }
+
+    #[test]
+    fn resolve_with_retransmit() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            // drop the first request to force a retransmit
+            server.recv_from(&mut buf).unwrap();
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x04sqlexpress\x00");
+            server.send_to(&response(RESP_DATA), peer).unwrap();
+        });
+
+        let future = ResolveInstance::new(addr, "sqlexpress", Some(Duration::from_secs(3)), 2);
+        let info = Runtime::new().unwrap().block_on(future.unwrap()).unwrap();
+        assert_eq!(info.instance_name, "SQLEXPRESS");
+        assert_eq!(info.tcp_port, Some(49172));
+    }
+
+    #[test]
+    fn resolve_unknown_instance() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (_, peer) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&response(RESP_DATA), peer).unwrap();
+        });
+
+        let future = ResolveInstance::new(addr, "NOPE", Some(Duration::from_secs(3)), 0);
+        match Runtime::new().unwrap().block_on(future.unwrap()) {
+            Err(Error::InstanceNotFound(ref name)) if name == "NOPE" => (),
+            x => panic!("expected an unknown instance, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn resolve_without_answer() {
+        // a browser that never answers, as it does for unknown instances
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let future = ResolveInstance::new(addr, "NOPE", Some(Duration::from_millis(300)), 1);
+        match Runtime::new().unwrap().block_on(future.unwrap()) {
+            Err(Error::BrowserTimeout(ref name)) if name == "NOPE" => (),
+            x => panic!("expected a timeout, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn discover_instances_of_host() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x03");
+            server.send_to(&response(RESP_DATA), peer).unwrap();
+        });
+
+        // completes with the answer instead of waiting for the timeout
+        let start = Instant::now();
+        let future = discover_instances("127.0.0.1", addr.port(), Duration::from_secs(5));
+        let instances = Runtime::new().unwrap().block_on(future).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let names: Vec<_> = instances.iter().map(|x| x.instance_name.as_str()).collect();
+        assert_eq!(names, vec!["SQLEXPRESS", "CLUSTERED"]);
+        assert!(instances[1].clustered);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let future = discover_instances("127.0.0.1", server.local_addr().unwrap().port(), Duration::from_millis(300));
+        match Runtime::new().unwrap().block_on(future) {
+            Err(Error::BrowserTimeout(ref host)) if host == "127.0.0.1" => (),
+            x => panic!("expected a timeout, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn broadcast_collects_answers() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x02");
+            server.send_to(&response(RESP_DATA), peer).unwrap();
+            // garbage (e.g. from other services answering a broadcast) is ignored
+            server.send_to(b"\x01\x02", peer).unwrap();
+            server.send_to(&response("ServerName;OTHER;InstanceName;MSSQLSERVER;tcp;1433;;"), peer).unwrap();
+        });
+
+        let future = broadcast_instances(addr.ip(), addr.port(), Duration::from_millis(500));
+        let instances = Runtime::new().unwrap().block_on(future).unwrap();
+        let names: Vec<_> = instances.iter().map(|x| x.instance_name.as_str()).collect();
+        assert_eq!(names, vec!["SQLEXPRESS", "CLUSTERED", "MSSQLSERVER"]);
+    }
+}
@@ -0,0 +1,466 @@
+//! Kerberos authentication using the GSS-API [RFC 2744] of the system (MIT Kerberos),
+//! which uses the credential cache of the process (e.g. obtained using `kinit` or a keytab)
+//!
+//! [RFC 2744] https://tools.ietf.org/html/rfc2744
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use winauth::NextBytes;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub type OM_uint32 = u32;
+    pub type gss_name_t = *mut c_void;
+    pub type gss_ctx_id_t = *mut c_void;
+    pub type gss_cred_id_t = *mut c_void;
+    pub type gss_OID = *mut gss_OID_desc;
+
+    #[repr(C)]
+    pub struct gss_OID_desc {
+        pub length: OM_uint32,
+        pub elements: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct gss_buffer_desc {
+        pub length: usize,
+        pub value: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct gss_key_value_element_desc {
+        pub key: *const c_char,
+        pub value: *const c_char,
+    }
+
+    #[repr(C)]
+    pub struct gss_key_value_set_desc {
+        pub count: OM_uint32,
+        pub elements: *mut gss_key_value_element_desc,
+    }
+
+    #[repr(C)]
+    pub struct gss_channel_bindings_struct {
+        pub initiator_addrtype: OM_uint32,
+        pub initiator_address: gss_buffer_desc,
+        pub acceptor_addrtype: OM_uint32,
+        pub acceptor_address: gss_buffer_desc,
+        pub application_data: gss_buffer_desc,
+    }
+
+    pub const GSS_S_COMPLETE: OM_uint32 = 0;
+    pub const GSS_S_CONTINUE_NEEDED: OM_uint32 = 1;
+    pub const GSS_C_MUTUAL_FLAG: OM_uint32 = 2;
+    pub const GSS_C_REPLAY_FLAG: OM_uint32 = 4;
+    pub const GSS_C_SEQUENCE_FLAG: OM_uint32 = 8;
+    pub const GSS_C_INITIATE: c_int = 1;
+    pub const GSS_C_GSS_CODE: c_int = 1;
+    pub const GSS_C_MECH_CODE: c_int = 2;
+
+    #[link(name = "gssapi_krb5")]
+    extern "C" {
+        pub static GSS_KRB5_NT_PRINCIPAL_NAME: gss_OID;
+
+        pub fn gss_import_name(
+            minor_status: *mut OM_uint32,
+            input_name_buffer: *mut gss_buffer_desc,
+            input_name_type: gss_OID,
+            output_name: *mut gss_name_t,
+        ) -> OM_uint32;
+
+        /// an extension of MIT Kerberos (1.11) to acquire credentials from a given credential store
+        pub fn gss_acquire_cred_from(
+            minor_status: *mut OM_uint32,
+            desired_name: gss_name_t,
+            time_req: OM_uint32,
+            desired_mechs: *mut c_void,
+            cred_usage: c_int,
+            cred_store: *const gss_key_value_set_desc,
+            output_cred_handle: *mut gss_cred_id_t,
+            actual_mechs: *mut c_void,
+            time_rec: *mut OM_uint32,
+        ) -> OM_uint32;
+
+        pub fn gss_release_cred(minor_status: *mut OM_uint32, cred_handle: *mut gss_cred_id_t) -> OM_uint32;
+
+        pub fn gss_init_sec_context(
+            minor_status: *mut OM_uint32,
+            initiator_cred_handle: gss_cred_id_t,
+            context_handle: *mut gss_ctx_id_t,
+            target_name: gss_name_t,
+            mech_type: gss_OID,
+            req_flags: OM_uint32,
+            time_req: OM_uint32,
+            input_chan_bindings: *mut gss_channel_bindings_struct,
+            input_token: *mut gss_buffer_desc,
+            actual_mech_type: *mut gss_OID,
+            output_token: *mut gss_buffer_desc,
+            ret_flags: *mut OM_uint32,
+            time_rec: *mut OM_uint32,
+        ) -> OM_uint32;
+
+        pub fn gss_display_status(
+            minor_status: *mut OM_uint32,
+            status_value: OM_uint32,
+            status_type: c_int,
+            mech_type: gss_OID,
+            message_context: *mut OM_uint32,
+            status_string: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+
+        pub fn gss_release_buffer(minor_status: *mut OM_uint32, buffer: *mut gss_buffer_desc) -> OM_uint32;
+
+        pub fn gss_release_name(minor_status: *mut OM_uint32, name: *mut gss_name_t) -> OM_uint32;
+
+        pub fn gss_delete_sec_context(
+            minor_status: *mut OM_uint32,
+            context_handle: *mut gss_ctx_id_t,
+            output_token: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+    }
+}
+
+use self::ffi::*;
+
+/// The OID of the Kerberos V5 mechanism (1.2.840.113554.1.2.2)
+static KRB5_MECH: [u8; 9] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02];
+
+fn empty_buffer() -> gss_buffer_desc {
+    gss_buffer_desc {
+        length: 0,
+        value: ptr::null_mut(),
+    }
+}
+
+fn buffer(data: &[u8]) -> gss_buffer_desc {
+    gss_buffer_desc {
+        length: data.len(),
+        value: data.as_ptr() as *mut c_void,
+    }
+}
+
+/// collect the messages describing a status code (of the given type)
+fn display_status(status: OM_uint32, status_type: i32, out: &mut Vec<String>) {
+    let mut message_context = 0;
+    loop {
+        let (mut minor, mut message) = (0, empty_buffer());
+        let ret = unsafe {
+            gss_display_status(&mut minor, status, status_type, ptr::null_mut(), &mut message_context, &mut message)
+        };
+        if ret != GSS_S_COMPLETE {
+            break;
+        }
+        if !message.value.is_null() {
+            let data = unsafe { slice::from_raw_parts(message.value as *const u8, message.length) };
+            out.push(String::from_utf8_lossy(data).into_owned());
+            unsafe { gss_release_buffer(&mut minor, &mut message) };
+        }
+        if message_context == 0 {
+            break;
+        }
+    }
+}
+
+fn gss_error(what: &str, major: OM_uint32, minor: OM_uint32) -> io::Error {
+    let mut messages = vec![];
+    display_status(major, GSS_C_GSS_CODE, &mut messages);
+    display_status(minor, GSS_C_MECH_CODE, &mut messages);
+    io::Error::other(format!("gssapi: {} failed: {}", what, messages.join(", ")))
+}
+
+/// Acquire the credentials of the principal of the given credential cache (e.g. `FILE:/tmp/krb5cc`)
+fn acquire_credentials(ccache: &str) -> io::Result<gss_cred_id_t> {
+    let key = CString::new("ccache").unwrap();
+    let value = CString::new(ccache).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "gssapi: invalid ccache"))?;
+    let mut element = gss_key_value_element_desc {
+        key: key.as_ptr(),
+        value: value.as_ptr(),
+    };
+    let store = gss_key_value_set_desc {
+        count: 1,
+        elements: &mut element,
+    };
+    let (mut minor, mut credentials) = (0, ptr::null_mut());
+    let major = unsafe {
+        gss_acquire_cred_from(
+            &mut minor,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            GSS_C_INITIATE,
+            &store,
+            &mut credentials,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if major != GSS_S_COMPLETE {
+        return Err(gss_error("acquiring the credentials", major, minor));
+    }
+    Ok(credentials)
+}
+
+/// A Kerberos client which authenticates as the principal of a credential cache
+pub struct GssapiClient {
+    target: gss_name_t,
+    /// the credentials to use, the ones of the default credential cache if null
+    credentials: gss_cred_id_t,
+    context: gss_ctx_id_t,
+    channel_bindings: Option<Vec<u8>>,
+    complete: bool,
+}
+
+// the handles are only used by the owner and the library does not tie them to a thread
+unsafe impl Send for GssapiClient {}
+
+impl GssapiClient {
+    /// Authenticate against the service principal `spn` (e.g. `MSSQLSvc/host:1433`) using the given
+    /// credential cache or else the default one, optionally binding the authentication to the given channel
+    pub fn new(spn: &str, ccache: Option<&str>, channel_bindings: Option<Vec<u8>>) -> io::Result<GssapiClient> {
+        let mut credentials = match ccache {
+            Some(ccache) => acquire_credentials(ccache)?,
+            None => ptr::null_mut(),
+        };
+        let mut name = buffer(spn.as_bytes());
+        let (mut minor, mut target) = (0, ptr::null_mut());
+        let major = unsafe { gss_import_name(&mut minor, &mut name, GSS_KRB5_NT_PRINCIPAL_NAME, &mut target) };
+        if major != GSS_S_COMPLETE {
+            if !credentials.is_null() {
+                unsafe { gss_release_cred(&mut minor, &mut credentials) };
+            }
+            return Err(gss_error("importing the service principal name", major, minor));
+        }
+        Ok(GssapiClient {
+            target,
+            credentials,
+            context: ptr::null_mut(),
+            channel_bindings,
+            complete: false,
+        })
+    }
+}
+
+impl NextBytes for GssapiClient {
+    fn next_bytes(&mut self, bytes: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+        if self.complete {
+            return Ok(None);
+        }
+        let mut mech = gss_OID_desc {
+            length: KRB5_MECH.len() as OM_uint32,
+            elements: KRB5_MECH.as_ptr() as *mut c_void,
+        };
+        let mut bindings = self.channel_bindings.as_ref().map(|data| gss_channel_bindings_struct {
+            initiator_addrtype: 0,
+            initiator_address: empty_buffer(),
+            acceptor_addrtype: 0,
+            acceptor_address: empty_buffer(),
+            application_data: buffer(data),
+        });
+        let bindings_ptr = bindings.as_mut().map_or(ptr::null_mut(), |x| x as *mut _);
+        let mut input = bytes.map_or_else(empty_buffer, buffer);
+        let (mut minor, mut output) = (0, empty_buffer());
+
+        let major = unsafe {
+            gss_init_sec_context(
+                &mut minor,
+                self.credentials,
+                &mut self.context,
+                self.target,
+                &mut mech,
+                GSS_C_MUTUAL_FLAG | GSS_C_REPLAY_FLAG | GSS_C_SEQUENCE_FLAG,
+                0,
+                bindings_ptr,
+                &mut input,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let token = if output.value.is_null() || output.length == 0 {
+            None
+        } else {
+            let data = unsafe { slice::from_raw_parts(output.value as *const u8, output.length) };
+            Some(data.to_vec())
+        };
+        unsafe { gss_release_buffer(&mut 0, &mut output) };
+
+        match major {
+            GSS_S_COMPLETE => self.complete = true,
+            GSS_S_CONTINUE_NEEDED => (),
+            _ => return Err(gss_error("initializing the security context", major, minor)),
+        }
+        Ok(token)
+    }
+}
+
+impl Drop for GssapiClient {
+    fn drop(&mut self) {
+        let mut minor = 0;
+        unsafe {
+            if !self.context.is_null() {
+                gss_delete_sec_context(&mut minor, &mut self.context, ptr::null_mut());
+            }
+            gss_release_name(&mut minor, &mut self.target);
+            if !self.credentials.is_null() {
+                gss_release_cred(&mut minor, &mut self.credentials);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::CString;
+    use std::ptr;
+    use std::slice;
+    use winauth::NextBytes;
+    use super::ffi::*;
+    use super::{buffer, empty_buffer, GssapiClient};
+
+    const GSS_C_ACCEPT: i32 = 2;
+    const GSS_S_BAD_BINDINGS: OM_uint32 = 1 << 16;
+
+    #[link(name = "gssapi_krb5")]
+    extern "C" {
+        fn gss_accept_sec_context(
+            minor_status: *mut OM_uint32,
+            context_handle: *mut gss_ctx_id_t,
+            acceptor_cred_handle: gss_cred_id_t,
+            input_token_buffer: *mut gss_buffer_desc,
+            input_chan_bindings: *mut gss_channel_bindings_struct,
+            src_name: *mut gss_name_t,
+            mech_type: *mut gss_OID,
+            output_token: *mut gss_buffer_desc,
+            ret_flags: *mut OM_uint32,
+            time_rec: *mut OM_uint32,
+            delegated_cred_handle: *mut gss_cred_id_t,
+        ) -> OM_uint32;
+    }
+
+    /// The service side: accept the initial context token using the keys of `keytab`, verifying the
+    /// channel bindings if given. Returns the major status and the AP-REP.
+    fn accept(keytab: &str, token: &[u8], channel_bindings: Option<&[u8]>) -> (OM_uint32, Vec<u8>) {
+        let (key, value) = (CString::new("keytab").unwrap(), CString::new(keytab).unwrap());
+        let mut element = gss_key_value_element_desc {
+            key: key.as_ptr(),
+            value: value.as_ptr(),
+        };
+        let store = gss_key_value_set_desc {
+            count: 1,
+            elements: &mut element,
+        };
+        let (mut minor, mut credentials, mut context) = (0, ptr::null_mut(), ptr::null_mut());
+        let major = unsafe {
+            gss_acquire_cred_from(
+                &mut minor,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                GSS_C_ACCEPT,
+                &store,
+                &mut credentials,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(major, GSS_S_COMPLETE, "acquiring the credentials of the service failed");
+
+        let mut bindings = channel_bindings.map(|data| gss_channel_bindings_struct {
+            initiator_addrtype: 0,
+            initiator_address: empty_buffer(),
+            acceptor_addrtype: 0,
+            acceptor_address: empty_buffer(),
+            application_data: buffer(data),
+        });
+        let bindings_ptr = bindings.as_mut().map_or(ptr::null_mut(), |x| x as *mut _);
+        let (mut input, mut output) = (buffer(token), empty_buffer());
+        let major = unsafe {
+            gss_accept_sec_context(
+                &mut minor,
+                &mut context,
+                credentials,
+                &mut input,
+                bindings_ptr,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let reply = if output.value.is_null() {
+            vec![]
+        } else {
+            unsafe { slice::from_raw_parts(output.value as *const u8, output.length) }.to_vec()
+        };
+        unsafe {
+            gss_release_buffer(&mut minor, &mut output);
+            if !context.is_null() {
+                gss_delete_sec_context(&mut minor, &mut context, ptr::null_mut());
+            }
+            gss_release_cred(&mut minor, &mut credentials);
+        }
+        (major, reply)
+    }
+
+    #[test]
+    fn missing_credentials() {
+        let ccache = Some("FILE:/nonexistent/tiberius_krb5cc");
+        match GssapiClient::new("MSSQLSvc/localhost:1433", ccache, None) {
+            Err(err) => assert!(err.to_string().starts_with("gssapi: acquiring the credentials failed")),
+            Ok(_) => panic!("expected the credential cache to be missing"),
+        }
+    }
+
+    /// Requires a KDC, see `krb5_test.sh` which creates a throwaway realm and runs these tests:
+    /// - `TIBERIUS_TEST_KRB5_CCACHE`: a credential cache holding a ticket granting ticket of a user
+    ///   (e.g. `kinit -c FILE:/tmp/krb5cc user`)
+    /// - `TIBERIUS_TEST_KRB5_SPN`: the SPN of a service in the same realm (e.g. `MSSQLSvc/sql.example.com:1433`)
+    /// - `TIBERIUS_TEST_KRB5_KEYTAB`: a keytab holding the key of this service (e.g. `ktadd -k /tmp/sql.keytab`)
+    #[test]
+    #[ignore]
+    fn initial_token_from_kdc() {
+        let ccache = env::var("TIBERIUS_TEST_KRB5_CCACHE").unwrap();
+        let spn = env::var("TIBERIUS_TEST_KRB5_SPN").unwrap();
+        let keytab = env::var("TIBERIUS_TEST_KRB5_KEYTAB").unwrap();
+        let mut client = GssapiClient::new(&spn, Some(&ccache), None).unwrap();
+        // the AP-REQ within the initial context token [RFC 2743 3.1], awaiting the AP-REP (mutual authentication)
+        let token = client.next_bytes(None).unwrap().unwrap();
+        assert_eq!(token[0], 0x60);
+        assert!(!client.complete);
+
+        let (major, reply) = accept(&keytab, &token, None);
+        assert_eq!(major, GSS_S_COMPLETE);
+        assert_eq!(client.next_bytes(Some(&reply)).unwrap(), None);
+        assert!(client.complete);
+    }
+
+    /// The `application_data` of the channel bindings must match the one of the service, see `initial_token_from_kdc`
+    #[test]
+    #[ignore]
+    fn channel_bindings_verified_by_service() {
+        let ccache = env::var("TIBERIUS_TEST_KRB5_CCACHE").unwrap();
+        let spn = env::var("TIBERIUS_TEST_KRB5_SPN").unwrap();
+        let keytab = env::var("TIBERIUS_TEST_KRB5_KEYTAB").unwrap();
+        let bindings = [b"tls-server-end-point:" as &[u8], &[0x5a; 32]].concat();
+
+        let mut client = GssapiClient::new(&spn, Some(&ccache), Some(bindings.clone())).unwrap();
+        let token = client.next_bytes(None).unwrap().unwrap();
+        let (major, reply) = accept(&keytab, &token, Some(&bindings));
+        assert_eq!(major, GSS_S_COMPLETE);
+        assert_eq!(client.next_bytes(Some(&reply)).unwrap(), None);
+
+        // a service behind another TLS channel (e.g. a man in the middle) computes other bindings
+        let mut client = GssapiClient::new(&spn, Some(&ccache), Some(bindings.clone())).unwrap();
+        let token = client.next_bytes(None).unwrap().unwrap();
+        let other = [b"tls-server-end-point:" as &[u8], &[0xa5; 32]].concat();
+        assert_eq!(accept(&keytab, &token, Some(&other)).0 & 0x00ff_0000, GSS_S_BAD_BINDINGS);
+    }
+}
@@ -1,9 +1,8 @@
 //! protocol abstraction, mostly type definitions (meta)
 use std::borrow::Cow;
 use std::cmp;
-use std::io::{self, Cursor, Write};
+use std::io::{self, Cursor, Read, Write};
 use std::mem;
-use futures::Sink;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use transport::{Io, TdsTransport, TdsTransportInner};
 use {FromUint, Error, Result, DRIVER_VERSION};
@@ -117,6 +116,8 @@ impl PacketHeader {
 
 
 uint_enum! {
+    /// The TDS version
+    #[derive(PartialEq)]
     #[repr(u32)]
     pub enum FeatureLevel {
         SqlServerV7 = 0x70000000,
@@ -143,6 +144,8 @@ uint_enum! {
         NotSupported = 2,
         /// Encrypt everything and fail if not possible
         Required = 3,
+        /// TDS 8.0: Encrypt everything, starting with the prelogin, and always validate the certificate
+        Strict = 4,
     }
 }
 
@@ -155,10 +158,16 @@ pub struct PreloginMessage {
     pub sub_build: u16,
     /// token=0x01
     pub encryption: EncryptionLevel,
+    /// whether certificate based authentication is requested (ENCRYPT_CLIENT_CERT), token=0x01
+    pub client_cert: bool,
     /// [client] threadid for debugging purposes, token=0x03
     pub thread_id: u32,
     /// token=0x04
     pub mars: bool,
+    /// [client] whether federated authentication is used, [server] whether the client has to echo this, token=0x06
+    pub fed_auth_required: bool,
+    /// [server] the nonce to include into the federated authentication, token=0x07
+    pub nonce: Option<[u8; 32]>,
 }
 
 impl PreloginMessage {
@@ -167,44 +176,51 @@ impl PreloginMessage {
             version: *DRIVER_VERSION as u32,
             sub_build: (*DRIVER_VERSION >> 32) as u16,
             encryption: EncryptionLevel::NotSupported,
+            client_cert: false,
             thread_id: 0,
             mars: false,
+            fed_auth_required: false,
+            nonce: None,
         }
     }
 }
 
 impl SerializeMessage for PreloginMessage {
     fn serialize_message<I: Io>(&self, ctx: &mut TdsTransport<I>) -> io::Result<Vec<u8>> {
-        let mut cursor = Cursor::new(vec![0; HEADER_BYTES + 21]);
+        let mut options = vec![
+            (0x00, 0x04 + 0x02), // version + subbuild
+            (0x01, 0x01),        // encryption
+            (0x03, 0x04),        // threadid
+            (0x04, 0x01),        // MARS
+        ];
+        if self.fed_auth_required {
+            options.push((0x06, 0x01));
+        }
+        let mut cursor = Cursor::new(vec![0; HEADER_BYTES]);
         cursor.set_position(8);
 
         // build the packet-body
         // offset = PL_OPTION_TOKEN + PL_OFFSET + PL_OPTION_LENGTH = 5 bytes + the terminator (0xFF)
-        let mut data_offset = 4 * 5 + 1;
+        let mut data_offset = options.len() as u16 * 5 + 1;
 
         // write the offsets
-        {
-            let mut write_option = |token: u8, length: u16| -> io::Result<()> {
-                cursor.write_u8(token)?;
-                cursor.write_u16::<BigEndian>(data_offset)?;
-                cursor.write_u16::<BigEndian>(length)?;
-                data_offset += length;
-                Ok(())
-            };
-
-            write_option(0x00, 0x04 + 0x02)?; // version + subbuild
-            write_option(0x01, 0x01)?; // encryption
-            write_option(0x03, 0x04)?; // threadid
-            write_option(0x04, 0x01)?; // MARS
+        for &(token, length) in &options {
+            cursor.write_u8(token)?;
+            cursor.write_u16::<BigEndian>(data_offset)?;
+            cursor.write_u16::<BigEndian>(length)?;
+            data_offset += length;
         }
         cursor.write_u8(0xff)?;
 
         // write the data (body of the options)
         cursor.write_u32::<BigEndian>(self.version as u32)?;
         cursor.write_u16::<BigEndian>(self.sub_build as u16)?;
-        cursor.write_u8(self.encryption as u8)?;
+        cursor.write_u8(self.encryption as u8 | if self.client_cert { 0x80 } else { 0 })?;
         cursor.write_u32::<BigEndian>(self.thread_id)?;
         cursor.write_u8(self.mars as u8)?;
+        if self.fed_auth_required {
+            cursor.write_u8(1)?;
+        }
 
         // build the header
         let header = PacketHeader {
@@ -232,6 +248,9 @@ impl<'a> UnserializeMessage<PreloginMessage> for &'a [u8] {
             }
             let offset = cursor.read_u16::<BigEndian>()?;
             let length = cursor.read_u16::<BigEndian>()?;
+            if offset as usize + length as usize > self.len() {
+                return Err(Error::Protocol(format!("prelogin option {} exceeds the message", token).into()));
+            }
             let old_pos = cursor.position();
             cursor.set_position(offset as u64);
             // verify whether the server acts in accordance to what we requested
@@ -246,13 +265,25 @@ impl<'a> UnserializeMessage<PreloginMessage> for &'a [u8] {
                 // encryption
                 1 => {
                     let encrypt = cursor.read_u8()?;
-                    ret.encryption = EncryptionLevel::from_u8(encrypt).ok_or(Error::Protocol(
-                        format!("invalid encryption value: {}", encrypt).into()
-                    ))?;
+                    ret.encryption = match EncryptionLevel::from_u8(encrypt & 0x7f) {
+                        // strict encryption is not negotiated using the prelogin
+                        Some(EncryptionLevel::Strict) | None => {
+                            return Err(Error::Protocol(format!("invalid encryption value: {}", encrypt).into()))
+                        }
+                        Some(level) => level,
+                    };
+                    ret.client_cert = encrypt & 0x80 != 0;
                 }
                 3 => debug_assert_eq!(length, 0), // threadid
                 4 => debug_assert_eq!(length, 1), // mars
-                _ => panic!("unsupported prelogin token: {}", token),
+                6 => ret.fed_auth_required = cursor.read_u8()? == 1,
+                7 => {
+                    let mut nonce = [0u8; 32];
+                    cursor.read_exact(&mut nonce)?;
+                    ret.nonce = Some(nonce);
+                }
+                // options which are not used (e.g. INSTOPT) or newer ones are skipped
+                _ => (),
             }
             cursor.set_position(old_pos);
         }
@@ -319,7 +350,55 @@ bitflags! {
         /// 0 = The server MUST restrict the collations sent to a specific set of collations.
         /// 1 = The server MAY send any collation that fits in the storage space.
         const SUPPORT_UNKNOWN_COLL = 0b00001000;
-        // TODO: fExtension?
+        /// 1 if the login contains a FeatureExt block (set when serializing)
+        const EXTENSION            = 0b00010000;
+    }
+}
+
+uint_enum! {
+    /// The id of a feature in the FeatureExt block of the login [2.2.6.4]
+    #[derive(PartialEq)]
+    #[repr(u8)]
+    pub enum FeatureId {
+        SessionRecovery = 0x01,
+        FedAuth = 0x02,
+        ColumnEncryption = 0x04,
+        GlobalTransactions = 0x05,
+        AzureSqlSupport = 0x08,
+        Utf8Support = 0x0A,
+        AzureSqlDnsCaching = 0x0B,
+    }
+}
+
+/// The FEDAUTH library [2.2.6.4]
+const FED_AUTH_LIBRARY_SECURITY_TOKEN: u8 = 0x01;
+const FED_AUTH_LIBRARY_MSAL: u8 = 0x02;
+
+/// The federated authentication requested in the FeatureExt of the login [2.2.6.4]
+pub enum FedAuth<'a> {
+    /// authenticate using a token acquired in advance
+    SecurityToken(Cow<'a, str>),
+    /// request a token once the server sent the FEDAUTHINFO token, using the given workflow
+    Msal(u8),
+}
+
+/// A feature requested in the FeatureExt block of the login [2.2.6.4]
+pub enum FeatureExt<'a> {
+    /// the session state to recover, which is empty for a new session
+    SessionRecovery(Cow<'a, [u8]>),
+    FedAuth(FedAuth<'a>),
+    AzureSqlSupport,
+    Utf8Support,
+}
+
+impl<'a> FeatureExt<'a> {
+    pub fn id(&self) -> FeatureId {
+        match *self {
+            FeatureExt::SessionRecovery(_) => FeatureId::SessionRecovery,
+            FeatureExt::FedAuth(_) => FeatureId::FedAuth,
+            FeatureExt::AzureSqlSupport => FeatureId::AzureSqlSupport,
+            FeatureExt::Utf8Support => FeatureId::Utf8Support,
+        }
     }
 }
 
@@ -351,10 +430,21 @@ pub struct LoginMessage<'a> {
     pub hostname: Cow<'a, str>,
     pub username: Cow<'a, str>,
     pub password: Cow<'a, str>,
+    /// the password to change the password of the login to (ChangePassword), if not empty
+    pub new_password: Cow<'a, str>,
     pub app_name: Cow<'a, str>,
     pub server_name: Cow<'a, str>,
+    /// the language of the session, the default language of the login if empty
+    pub language: Cow<'a, str>,
     /// the default database to connect to
     pub db_name: Cow<'a, str>,
+
+    /// the features to request (FeatureExt)
+    pub features: Vec<FeatureExt<'a>>,
+    /// whether the server signaled FEDAUTHREQUIRED in its prelogin response
+    pub fed_auth_echo: bool,
+    /// the nonce the server sent in its prelogin response
+    pub nonce: Option<[u8; 32]>,
 }
 
 impl<'a> LoginMessage<'a> {
@@ -370,24 +460,75 @@ impl<'a> LoginMessage<'a> {
             integrated_security: None,
             type_flags: LoginTypeFlags::empty(),
             option_flags_3: LoginOptionFlags3::SUPPORT_UNKNOWN_COLL,
-            client_timezone: 0, //TODO
-            client_lcid: 0,     // TODO
+            client_timezone: 0,
+            client_lcid: 0,
             hostname: "".into(),
             username: "".into(),
             password: "".into(),
+            new_password: "".into(),
             app_name: "".into(),
             server_name: "".into(),
+            language: "".into(),
             db_name: "".into(),
+            features: vec![],
+            fed_auth_echo: false,
+            nonce: None,
         }
     }
 }
 
+impl<'a> LoginMessage<'a> {
+    /// the FeatureExt block without its terminator
+    fn feature_ext(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        for feature in &self.features {
+            let mut data = vec![];
+            match *feature {
+                FeatureExt::SessionRecovery(ref state) => data.write_all(state)?,
+                FeatureExt::FedAuth(FedAuth::SecurityToken(ref token)) => {
+                    data.write_u8(FED_AUTH_LIBRARY_SECURITY_TOKEN << 1 | self.fed_auth_echo as u8)?;
+                    let token: Vec<u16> = token.encode_utf16().collect();
+                    data.write_u32::<LittleEndian>(token.len() as u32 * 2)?;
+                    for codepoint in token {
+                        data.write_u16::<LittleEndian>(codepoint)?;
+                    }
+                    if let Some(ref nonce) = self.nonce {
+                        data.write_all(nonce)?;
+                    }
+                }
+                FeatureExt::FedAuth(FedAuth::Msal(workflow)) => {
+                    data.write_u8(FED_AUTH_LIBRARY_MSAL << 1 | self.fed_auth_echo as u8)?;
+                    data.write_u8(workflow)?;
+                }
+                // no options are defined yet
+                FeatureExt::AzureSqlSupport => data.write_u8(0)?,
+                FeatureExt::Utf8Support => (),
+            }
+            buf.write_u8(feature.id() as u8)?;
+            buf.write_u32::<LittleEndian>(data.len() as u32)?;
+            buf.extend(data);
+        }
+        Ok(buf)
+    }
+}
+
 impl<'a> SerializeMessage for LoginMessage<'a> {
     fn serialize_message<I: Io>(&self, trans: &mut TdsTransport<I>) -> io::Result<Vec<u8>> {
         let mut cursor = Cursor::new(Vec::with_capacity(1 << 9));
 
         cursor.set_position(HEADER_BYTES as u64 + 4);
 
+        let feature_ext = self.feature_ext()?;
+        let mut option_flags3 = if feature_ext.is_empty() {
+            self.option_flags_3 & !LoginOptionFlags3::EXTENSION
+        } else {
+            self.option_flags_3 | LoginOptionFlags3::EXTENSION
+        };
+        // ignore the specified value for changing the password since we determine that by the struct field
+        option_flags3.set(LoginOptionFlags3::REQUEST_CHANGE_PWD, !self.new_password.is_empty());
+        // the offset of the DWORD which contains the offset of the FeatureExt block
+        let mut extension_offset = None;
+
         // ignore the specified value for integrated security since we determine that by the struct field
         let option_flags2 = if self.integrated_security.is_some() {
             self.option_flags_2 | LoginOptionFlags2::INTEGRATED_SECURITY
@@ -408,7 +549,7 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
             self.option_flags_1.bits(),
             option_flags2.bits(),
             self.type_flags.bits(),
-            self.option_flags_3.bits(),
+            option_flags3.bits(),
         ] {
             cursor.write_u8(*val)?;
         }
@@ -425,12 +566,12 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
             &self.server_name,
             &"".into(), // 5. ibExtension
             &"".into(), // ibCltIntName
-            &"".into(), // ibLanguage
+            &self.language,
             &self.db_name,
             &"".into(), // 9. ClientId (6 bytes); this is included in var_data so we don't lack the bytes of cbSspiLong (4=2*2) and can insert it at the correct position
             &"".into(), // 10. ibSSPI
             &"".into(), // ibAtchDBFile
-            &"".into(), // ibChangePassword
+            &self.new_password, // 12. ibChangePassword
         ];
 
         let mut data_offset = cursor.position() as usize + var_data.len() * 2 * 2 + 6;
@@ -443,6 +584,18 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
                 continue;
             }
             cursor.write_u16::<LittleEndian>((data_offset - HEADER_BYTES) as u16)?;
+            if i == 5 {
+                let length = if feature_ext.is_empty() {
+                    0
+                } else {
+                    // reserve the space for the offset, which is known once all data is written
+                    extension_offset = Some(data_offset);
+                    data_offset += 4;
+                    4
+                };
+                cursor.write_u16::<LittleEndian>(length)?;
+                continue;
+            }
             if i == 10 {
                 let length = if let Some(ref bytes) = self.integrated_security {
                     let bak = cursor.position();
@@ -465,8 +618,8 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
                 cursor.write_u16::<LittleEndian>(codepoint)?;
             }
             let new_position = cursor.position() as usize;
-            // prepare the password in MS-fashion
-            if i == 2 {
+            // prepare the password (and the new one) in MS-fashion
+            if i == 2 || i == 12 {
                 let buffer = cursor.get_mut();
                 for idx in data_offset..new_position {
                     let byte = buffer[idx];
@@ -484,8 +637,12 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
         // cbSSPILong
         cursor.write_u32::<LittleEndian>(0)?;
 
+        if let Some(offset) = extension_offset {
+            cursor.set_position(offset as u64);
+            cursor.write_u32::<LittleEndian>((data_offset - HEADER_BYTES) as u32)?;
+        }
         cursor.set_position(data_offset as u64);
-        // FeatureExt: unsupported for now, simply write a terminator
+        cursor.write_all(&feature_ext)?;
         cursor.write_u8(0xFF)?;
 
         // build the header
@@ -503,6 +660,38 @@ impl<'a> SerializeMessage for LoginMessage<'a> {
     }
 }
 
+/// The Federated Authentication Token message [2.2.6.3] sending the token the server asked for
+pub struct FedAuthTokenMessage<'a> {
+    pub token: &'a str,
+    /// the nonce the server sent in its prelogin response
+    pub nonce: Option<[u8; 32]>,
+}
+
+impl<'a> SerializeMessage for FedAuthTokenMessage<'a> {
+    fn serialize_message<I: Io>(&self, ctx: &mut TdsTransport<I>) -> io::Result<Vec<u8>> {
+        let token: Vec<u16> = self.token.encode_utf16().collect();
+        let nonce_len = self.nonce.map_or(0, |x| x.len());
+        let mut buf = vec![0; HEADER_BYTES];
+        buf.write_u32::<LittleEndian>((4 + token.len() * 2 + nonce_len) as u32)?;
+        buf.write_u32::<LittleEndian>(token.len() as u32 * 2)?;
+        for codepoint in token {
+            buf.write_u16::<LittleEndian>(codepoint)?;
+        }
+        if let Some(ref nonce) = self.nonce {
+            buf.write_all(nonce)?;
+        }
+
+        // build the header
+        let header = PacketHeader {
+            ty: PacketType::Fat,
+            status: PacketStatus::EndOfMessage,
+            ..PacketHeader::new(buf.len(), ctx.next_id())
+        };
+        header.serialize(&mut buf)?;
+        Ok(buf)
+    }
+}
+
 pub struct SspiMessage(pub Vec<u8>);
 
 impl SerializeMessage for SspiMessage {
@@ -549,6 +738,7 @@ pub fn write_sql_batch<I: Io>(trans: &mut TdsTransport<I>, query: &str) -> io::R
         ..PacketHeader::new(0, 0)
     };
 
+    trans.start_request();
     let mut writer = PacketWriter::new(&mut trans.inner, header);
     write_trans_descriptor(&mut writer, trans.transaction)?;
 
@@ -592,20 +782,16 @@ impl<'a, I: 'a + Io> PacketWriter<'a, I> {
 
 impl<'a, I: Io> Write for PacketWriter<'a, I> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // fast path for small writes
-        if self.buf.capacity() - self.buf.len() > buf.len() {
-            self.buf.extend_from_slice(buf);
-            return Ok(buf.len());
-        }
-
+        // every packet but the last one of a message has to be of the negotiated size,
+        // a full packet is only queued once more data follows (else `finalize` queues it)
+        let packet_size = self.transport.packet_size;
         let mut pending = buf;
         while !pending.is_empty() {
-            let max_bytes = self.buf.capacity() - self.buf.len();
-            let (fitting, next) = pending.split_at(cmp::min(max_bytes, pending.len()));
-            self.buf.extend_from_slice(fitting);
-            if self.buf.capacity() <= self.buf.len() {
+            if self.buf.len() >= packet_size {
                 self.flush()?;
             }
+            let (fitting, next) = pending.split_at(cmp::min(packet_size - self.buf.len(), pending.len()));
+            self.buf.extend_from_slice(fitting);
             pending = next;
         }
         Ok(buf.len())
@@ -620,7 +806,93 @@ impl<'a, I: Io> Write for PacketWriter<'a, I> {
             self.header.serialize(&mut buf)?;
             self.transport.queue_vec(buf);
         }
-        let _ = self.transport.poll_complete()?;
+        // the packets are sent once the request is polled, which allows to recover a broken session first
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use futures::Sink;
+    use transport::TdsTransport;
+    use super::{EncryptionLevel, PacketHeader, PacketStatus, PacketType, PacketWriter, PreloginMessage,
+                SerializeMessage, UnserializeMessage, HEADER_BYTES};
+    use Result;
+
+    /// write a message of `len` bytes and return the length and status of every packet
+    fn write_message(packet_size: usize, len: usize) -> Vec<(usize, u8)> {
+        let mut trans = TdsTransport::new(Cursor::new(vec![]));
+        trans.inner.packet_size = packet_size;
+        let header = PacketHeader {
+            ty: PacketType::SQLBatch,
+            status: PacketStatus::NormalMessage,
+            ..PacketHeader::new(0, 0)
+        };
+        {
+            let mut writer = PacketWriter::new(&mut trans.inner, header);
+            // a small write followed by one spanning several packets
+            let data: Vec<u8> = (0..len).map(|x| x as u8).collect();
+            writer.write_all(&data[..len.min(10)]).unwrap();
+            writer.write_all(&data[len.min(10)..]).unwrap();
+            writer.finalize().unwrap();
+        }
+        trans.inner.poll_complete().unwrap();
+
+        let mut written = &trans.inner.io.get_ref()[..];
+        let mut payload = vec![];
+        let mut packets = vec![];
+        while !written.is_empty() {
+            let length = (written[2] as usize) << 8 | written[3] as usize;
+            payload.extend_from_slice(&written[HEADER_BYTES..length]);
+            packets.push((length, written[1]));
+            written = &written[length..];
+        }
+        assert!(payload.iter().enumerate().all(|(i, &x)| x == i as u8));
+        assert_eq!(payload.len(), len);
+        packets
+    }
+
+    #[test]
+    fn packet_writer_uses_packet_size() {
+        for &packet_size in &[512, 4096, 32767] {
+            let payload_size = packet_size - HEADER_BYTES;
+            let packets = write_message(packet_size, 2 * payload_size + 100);
+            assert_eq!(packets, vec![(packet_size, 0), (packet_size, 0), (100 + HEADER_BYTES, 1)]);
+
+            // a message filling the last packet exactly is not followed by an empty packet
+            let packets = write_message(packet_size, 2 * payload_size);
+            assert_eq!(packets, vec![(packet_size, 0), (packet_size, 1)]);
+        }
+        assert_eq!(write_message(4096, 0), vec![(HEADER_BYTES, 1)]);
+    }
+
+    #[test]
+    fn prelogin_encryption() {
+        let mut trans = TdsTransport::new(Cursor::new(vec![]));
+        let parse = |trans: &mut TdsTransport<_>, encryption: u8| {
+            let data = [0x01, 0x00, 0x06, 0x00, 0x01, 0xff, encryption];
+            let msg: Result<PreloginMessage> = (&data[..]).unserialize_message(trans);
+            msg.map(|msg| (msg.encryption, msg.client_cert))
+        };
+        assert_eq!(parse(&mut trans, 0x03).unwrap(), (EncryptionLevel::Required, false));
+        assert_eq!(parse(&mut trans, 0x83).unwrap(), (EncryptionLevel::Required, true));
+        assert_eq!(parse(&mut trans, 0x81).unwrap(), (EncryptionLevel::On, true));
+        assert!(parse(&mut trans, 0x04).is_err());
+
+        // unknown options are skipped, an option beyond the message is rejected
+        let data = [0x42, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, 0x0d, 0x00, 0x01, 0xff, 0xab, 0xcd, 0x03];
+        let msg: PreloginMessage = (&data[..]).unserialize_message(&mut trans).unwrap();
+        assert_eq!(msg.encryption, EncryptionLevel::Required);
+        let data = [0x42, 0x00, 0x06, 0x00, 0x02, 0xff, 0xab];
+        let msg: Result<PreloginMessage> = (&data[..]).unserialize_message(&mut trans);
+        assert!(msg.is_err());
+
+        let mut msg = PreloginMessage::new();
+        msg.encryption = EncryptionLevel::On;
+        msg.client_cert = true;
+        let bytes = msg.serialize_message(&mut trans).unwrap();
+        // the encryption option follows the 4 options and the version
+        assert_eq!(bytes[HEADER_BYTES + 4 * 5 + 1 + 6], 0x81);
+    }
+}
@@ -1,11 +1,13 @@
 //! Query results and resultsets
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 use futures::{Async, Future, Poll, Sink, Stream};
 use futures::sync::oneshot;
 use futures_state_stream::{StateStream, StreamEvent};
+use tokio::timer::Delay;
 use tokens::{DoneStatus, TdsResponseToken, TokenRow};
 use types::FromColumnData;
-use {BoxableIo, SqlConnection, StmtResult, Error, Result};
+use {BoxableIo, CancelHandle, Cancelable, SqlConnection, StmtResult, Error, Result};
 
 /// A query result consists of multiple query streams (amount of executed queries = amount of results)
 #[must_use = "streams do nothing unless polled"]
@@ -13,6 +15,9 @@ pub struct ResultSetStream<I: BoxableIo, R: StmtResult<I>> {
     err: Option<Error>,
     conn: Option<SqlConnection<I>>,
     receiver: Option<oneshot::Receiver<SqlConnection<I>>>,
+    cancel: CancelHandle,
+    /// the time the server may take to finish the response, `None` once the deadline was set on the first poll
+    timeout: Option<Option<Duration>>,
     /// whether we already returned a result for the current resultset
     already_triggered: bool,
     done: bool,
@@ -23,6 +28,8 @@ impl<I: BoxableIo, R: StmtResult<I>> ResultSetStream<I, R> {
     pub fn new(conn: SqlConnection<I>) -> ResultSetStream<I, R> {
         ResultSetStream {
             err: None,
+            cancel: CancelHandle(conn.0.transport.cancel.clone()),
+            timeout: Some(conn.0.params.command_timeout),
             conn: Some(conn),
             receiver: None,
             already_triggered: false,
@@ -37,6 +44,18 @@ impl<I: BoxableIo, R: StmtResult<I>> ResultSetStream<I, R> {
     }
 }
 
+impl<I: BoxableIo, R: StmtResult<I>> Cancelable for ResultSetStream<I, R> {
+    fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if self.timeout.is_some() {
+            self.timeout = Some(timeout);
+        }
+    }
+}
+
 impl<I: BoxableIo, R: StmtResult<I>> StateStream for ResultSetStream<I, R> {
     type Item = R::Result;
     type State = SqlConnection<I>;
@@ -61,12 +80,17 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for ResultSetStream<I, R> {
 
         assert!(self.conn.is_some());
 
+        if let Some(timeout) = self.timeout.take() {
+            let transport = &mut self.conn.as_mut().unwrap().0.transport;
+            transport.deadline = timeout.map(|timeout| Delay::new(Instant::now() + timeout));
+        }
+
         if !self.done {
             let do_ret = match self.conn {
                 None => false,
                 Some(ref mut conn) => {
                     let inner = &mut conn.0;
-                    try_ready!(inner.transport.inner.poll_complete());
+                    try_ready!(inner.poll_send());
 
                     let token = try_ready!(inner.transport.next_token())
                         .expect("resultset: expected a token!");
@@ -99,6 +123,10 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for ResultSetStream<I, R> {
                 ));
             }
         }
+        // the connection is returned as the state when polled again after a timeout
+        if self.conn.as_mut().unwrap().0.transport.take_timed_out() {
+            return Err(Error::Timeout);
+        }
         let conn = self.conn.take().unwrap();
         Ok(Async::Ready(StreamEvent::Done(conn)))
     }
@@ -107,7 +135,9 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for ResultSetStream<I, R> {
 /// A stream of [`Rows`](struct.QueryRow.html) returned for the current resultset
 #[must_use = "streams do nothing unless polled"]
 pub struct QueryStream<I: BoxableIo> {
-    inner: ResultInner<I>
+    inner: ResultInner<I>,
+    /// Whether only a Done token (that was previously injected) is the contents of this stream
+    single_token: bool,
 }
 
 struct ResultInner<I: BoxableIo> (
@@ -153,7 +183,8 @@ impl<'a, I: BoxableIo> Stream for QueryStream<I> {
                     return Ok(Async::Ready(Some(QueryRow(row))));
                 }
                 // if this is the final done token, we need to reinject it for result set stream to handle it
-                TdsResponseToken::Done(ref done) if !done.status.contains(DoneStatus::MORE) => true,
+                // (unless the result set stream already handled it and reinjected it for us)
+                TdsResponseToken::Done(ref done) if !done.status.contains(DoneStatus::MORE) => !self.single_token,
                 TdsResponseToken::Done(_) | TdsResponseToken::DoneInProc(_) => false,
                 x => panic!("query: unexpected token: {:?}", x),
             };
@@ -172,6 +203,7 @@ impl<'a, I: BoxableIo> StmtResult<I> for QueryStream<I> {
 
     fn from_connection(conn: SqlConnection<I>, ret_conn: oneshot::Sender<SqlConnection<I>>) -> QueryStream<I> {
         QueryStream {
+            single_token: conn.0.transport.has_reinjected(),
             inner: ResultInner(Some((conn, ret_conn))),
         }
     }
@@ -201,10 +233,7 @@ impl<I: BoxableIo> Future for ExecFuture<I> {
             loop {
                 let token = try_ready!(inner.transport.next_token()).expect("exec: expected token");
                 let reinject = match token {
-                    TdsResponseToken::Row(_) => {
-                        self.single_token = false;
-                        false
-                    }
+                    TdsResponseToken::Row(_) => continue,
                     TdsResponseToken::Done(ref done) |
                     TdsResponseToken::DoneInProc(ref done) |
                     TdsResponseToken::DoneProc(ref done) => {
@@ -217,19 +246,15 @@ impl<I: BoxableIo> Future for ExecFuture<I> {
                             ret = done.done_rows;
                         }
                         // if this is the final done token, we need to reinject it for result set stream to handle it
-                        // (as in querying, if self.single_token it already was reinjected and would result in an infinite cycle)
-                        let reinject = !done.status.contains(DoneStatus::MORE) && !self.single_token
-                            && final_token;
-                        if !reinject {
-                            break;
-                        }
-                        true
+                        // (as in querying, unless the result set stream already reinjected it for us)
+                        !done.status.contains(DoneStatus::MORE) && !self.single_token && final_token
                     }
                     x => panic!("exec: unexpected token: {:?}", x),
                 };
                 if reinject {
                     inner.transport.reinject(token);
                 }
+                break;
             }
         }
 
@@ -246,8 +271,8 @@ impl<I: BoxableIo> StmtResult<I> for ExecFuture<I> {
         ret_conn: oneshot::Sender<SqlConnection<I>>,
     ) -> ExecFuture<I> {
         ExecFuture {
+            single_token: conn.0.transport.has_reinjected(),
             inner: ResultInner(Some((conn, ret_conn))),
-            single_token: true,
         }
     }
 }
@@ -0,0 +1,380 @@
+//! The Simple and Protected GSS-API Negotiation Mechanism (SPNEGO) [RFC 4178] used to wrap
+//! the NTLM messages of integrated authentication, as the Negotiate package of SSPI does
+//!
+//! [RFC 4178] https://tools.ietf.org/html/rfc4178
+//! [MS-NLMP] https://msdn.microsoft.com/en-us/library/cc236621.aspx
+use std::io;
+use byteorder::{ByteOrder, LittleEndian};
+use hmac::{Hmac, Mac};
+use md4::Md4;
+use md5::{Digest, Md5};
+use rc4::{KeyInit, Rc4, StreamCipher};
+use rc4::consts::U16;
+use winauth::{NextBytes, NtlmV2Client};
+
+/// The OID of SPNEGO (1.3.6.1.5.5.2)
+const SPNEGO_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+/// The OID of NTLMSSP (1.3.6.1.4.1.311.2.2.10)
+const NTLMSSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a];
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OID: u8 = 0x06;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_ENUMERATED: u8 = 0x0a;
+const DER_APPLICATION: u8 = 0x60;
+
+/// [RFC 4178 4.2.2] the values of `negState` in a NegTokenResp
+const ACCEPT_COMPLETED: u8 = 0;
+const ACCEPT_INCOMPLETE: u8 = 1;
+const REJECT: u8 = 2;
+const REQUEST_MIC: u8 = 3;
+
+/// [MS-NLMP 3.4.5.2] the magic constants to derive the signing and sealing keys
+const CLIENT_SIGNING: &[u8] = b"session key to client-to-server signing key magic constant\0";
+const CLIENT_SEALING: &[u8] = b"session key to client-to-server sealing key magic constant\0";
+const SERVER_SIGNING: &[u8] = b"session key to server-to-client signing key magic constant\0";
+const SERVER_SEALING: &[u8] = b"session key to server-to-client sealing key magic constant\0";
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// encode a DER element, using the long form of the length where required
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(content.len() + 4);
+    ret.push(tag);
+    match content.len() {
+        len if len < 0x80 => ret.push(len as u8),
+        len if len <= 0xff => ret.extend_from_slice(&[0x81, len as u8]),
+        len => ret.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    ret.extend_from_slice(content);
+    ret
+}
+
+/// decode the DER element at the start of `data`, returning its tag, its content and the remainder
+fn der_read(data: &[u8]) -> io::Result<(u8, &[u8], &[u8])> {
+    if data.len() < 2 {
+        return Err(invalid_data("spnego: truncated element"));
+    }
+    let (tag, first) = (data[0], data[1] as usize);
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let bytes = first & 0x7f;
+        if bytes == 0 || bytes > 4 || data.len() < 2 + bytes {
+            return Err(invalid_data("spnego: invalid element length"));
+        }
+        let len = data[2..2 + bytes].iter().fold(0, |len, &b| (len << 8) | b as usize);
+        (len, 2 + bytes)
+    };
+    if data.len() - start < len {
+        return Err(invalid_data("spnego: element exceeds the token"));
+    }
+    Ok((tag, &data[start..start + len], &data[start + len..]))
+}
+
+/// The DER encoding of the MechTypeList we offer, which is protected by the mechListMIC
+fn mech_type_list() -> Vec<u8> {
+    der(DER_SEQUENCE, &der(DER_OID, NTLMSSP_OID))
+}
+
+/// The fields of a NegTokenResp [RFC 4178 4.2.2]
+#[derive(Debug, Default)]
+struct NegTokenResp<'a> {
+    neg_state: Option<u8>,
+    supported_mech: Option<&'a [u8]>,
+    response_token: Option<&'a [u8]>,
+    mech_list_mic: Option<&'a [u8]>,
+}
+
+impl<'a> NegTokenResp<'a> {
+    fn decode(data: &'a [u8]) -> io::Result<NegTokenResp<'a>> {
+        let (tag, content, _) = der_read(data)?;
+        if tag != 0xa1 {
+            return Err(invalid_data("spnego: expected a NegTokenResp"));
+        }
+        let (tag, mut fields, _) = der_read(content)?;
+        if tag != DER_SEQUENCE {
+            return Err(invalid_data("spnego: expected a NegTokenResp sequence"));
+        }
+        let mut ret = NegTokenResp::default();
+        while !fields.is_empty() {
+            let (tag, field, rest) = der_read(fields)?;
+            let (inner_tag, value, _) = der_read(field)?;
+            match (tag, inner_tag) {
+                (0xa0, DER_ENUMERATED) if value.len() == 1 => ret.neg_state = Some(value[0]),
+                (0xa1, DER_OID) => ret.supported_mech = Some(value),
+                (0xa2, DER_OCTET_STRING) => ret.response_token = Some(value),
+                (0xa3, DER_OCTET_STRING) => ret.mech_list_mic = Some(value),
+                _ => return Err(invalid_data("spnego: unexpected NegTokenResp field")),
+            }
+            fields = rest;
+        }
+        Ok(ret)
+    }
+}
+
+/// [RFC 4178 4.2.1] wrap the NTLM NEGOTIATE message into the initial NegTokenInit
+fn encode_init(mech_token: &[u8]) -> Vec<u8> {
+    let mut token = der(0xa0, &mech_type_list());
+    token.extend(der(0xa2, &der(DER_OCTET_STRING, mech_token)));
+    let mut content = der(DER_OID, SPNEGO_OID);
+    content.extend(der(0xa0, &der(DER_SEQUENCE, &token)));
+    der(DER_APPLICATION, &content)
+}
+
+/// [RFC 4178 4.2.2] wrap the NTLM AUTHENTICATE message and the mechListMIC into a NegTokenResp
+fn encode_resp(response_token: &[u8], mech_list_mic: &[u8]) -> Vec<u8> {
+    let mut token = der(0xa2, &der(DER_OCTET_STRING, response_token));
+    token.extend(der(0xa3, &der(DER_OCTET_STRING, mech_list_mic)));
+    der(0xa1, &der(DER_SEQUENCE, &token))
+}
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// RC4 using a key of 16 bytes, the length of every key NTLM uses it with
+fn rc4(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let mut ret = data.to_vec();
+    Rc4::<U16>::new(key.into()).apply_keystream(&mut ret);
+    ret
+}
+
+/// NTOWFv2 [MS-NLMP 3.3.2], the key the responses of NTLMv2 are derived from
+fn ntowf_v2(domain: &str, user: &str, password: &str) -> [u8; 16] {
+    hmac_md5(&Md4::digest(ucs2(password)), &ucs2(&(user.to_uppercase() + domain)))
+}
+
+fn ucs2(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect()
+}
+
+/// Recover the ExportedSessionKey [MS-NLMP 3.1.5.1.2] from the AUTHENTICATE message the NTLM client
+/// produced, since it is required for signing but not exposed by the client
+fn exported_session_key(authenticate: &[u8], response_key: &[u8; 16]) -> io::Result<[u8; 16]> {
+    let field = |offset: usize| -> io::Result<&[u8]> {
+        if authenticate.len() < offset + 8 {
+            return Err(invalid_data("ntlm: truncated AUTHENTICATE message"));
+        }
+        let len = LittleEndian::read_u16(&authenticate[offset..]) as usize;
+        let start = LittleEndian::read_u32(&authenticate[offset + 4..]) as usize;
+        authenticate
+            .get(start..start + len)
+            .ok_or_else(|| invalid_data("ntlm: invalid AUTHENTICATE message field"))
+    };
+    let nt_proof_str = field(20)?
+        .get(..16)
+        .ok_or_else(|| invalid_data("ntlm: invalid NtChallengeResponse"))?;
+    let encrypted_key = field(52)?;
+    if encrypted_key.len() != 16 {
+        return Err(invalid_data("ntlm: expected a key exchange"));
+    }
+
+    // the SessionBaseKey (KeyExchangeKey) of NTLMv2 [3.3.2]
+    let session_base_key = hmac_md5(response_key, nt_proof_str);
+    let mut session_key = [0u8; 16];
+    session_key.copy_from_slice(&rc4(&session_base_key, encrypted_key));
+    Ok(session_key)
+}
+
+/// [MS-NLMP 3.4.4.2] the signature of a message with extended session security and key exchange
+/// (our sequence number is always 0, since only the mechListMIC is signed)
+fn ntlm_signature(session_key: &[u8; 16], signing: &[u8], sealing: &[u8], message: &[u8]) -> Vec<u8> {
+    let signing_key = md5(&[session_key as &[u8], signing].concat());
+    let sealing_key = md5(&[session_key as &[u8], sealing].concat());
+    let seq_num = [0u8; 4];
+    let checksum = hmac_md5(&signing_key, &[&seq_num as &[u8], message].concat());
+    let mut ret = vec![1, 0, 0, 0];
+    ret.extend(rc4(&sealing_key, &checksum[..8]));
+    ret.extend_from_slice(&seq_num);
+    ret
+}
+
+enum NegotiateState {
+    Initial,
+    Challenged,
+    Authenticated,
+}
+
+/// An NTLMv2 client wrapped in SPNEGO, so that it can be used where the Negotiate package
+/// is required instead of raw NTLM
+pub struct NegotiateClient {
+    state: NegotiateState,
+    ntlm: NtlmV2Client<'static>,
+    /// NTOWFv2 of the credentials, to recover the ExportedSessionKey the NTLM client does not expose
+    response_key: [u8; 16],
+    /// the ExportedSessionKey of the NTLM exchange, once authenticated
+    session_key: [u8; 16],
+}
+
+impl NegotiateClient {
+    /// Wrap `ntlm` which was built using the given credentials
+    pub fn new(ntlm: NtlmV2Client<'static>, domain: &str, user: &str, password: &str) -> NegotiateClient {
+        NegotiateClient {
+            state: NegotiateState::Initial,
+            ntlm,
+            response_key: ntowf_v2(domain, user, password),
+            session_key: [0; 16],
+        }
+    }
+}
+
+impl NextBytes for NegotiateClient {
+    fn next_bytes(&mut self, bytes: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+        match self.state {
+            NegotiateState::Initial => {
+                let negotiate = self.ntlm
+                    .next_bytes(bytes)?
+                    .ok_or_else(|| invalid_data("ntlm: expected a NEGOTIATE message"))?;
+                self.state = NegotiateState::Challenged;
+                Ok(Some(encode_init(&negotiate)))
+            }
+            NegotiateState::Challenged => {
+                let bytes = bytes.ok_or_else(|| invalid_data("spnego: expected a NegTokenResp"))?;
+                let resp = NegTokenResp::decode(bytes)?;
+                match resp.neg_state {
+                    Some(ACCEPT_INCOMPLETE) | Some(REQUEST_MIC) | None => (),
+                    Some(REJECT) => return Err(invalid_data("spnego: the server rejected the authentication")),
+                    Some(_) => return Err(invalid_data("spnego: unexpected negotiation state")),
+                }
+                match resp.supported_mech {
+                    Some(mech) if mech != NTLMSSP_OID => {
+                        return Err(invalid_data("spnego: the server did not select NTLM"))
+                    }
+                    _ => (),
+                }
+                let challenge = resp.response_token
+                    .ok_or_else(|| invalid_data("spnego: expected a CHALLENGE message"))?;
+                let authenticate = self.ntlm
+                    .next_bytes(Some(challenge))?
+                    .ok_or_else(|| invalid_data("ntlm: expected an AUTHENTICATE message"))?;
+                self.session_key = exported_session_key(&authenticate, &self.response_key)?;
+                let mic = ntlm_signature(&self.session_key, CLIENT_SIGNING, CLIENT_SEALING, &mech_type_list());
+                self.state = NegotiateState::Authenticated;
+                Ok(Some(encode_resp(&authenticate, &mic)))
+            }
+            NegotiateState::Authenticated => {
+                // the final NegTokenResp (if any) confirms the exchange
+                if let Some(bytes) = bytes {
+                    let resp = NegTokenResp::decode(bytes)?;
+                    match resp.neg_state {
+                        Some(ACCEPT_COMPLETED) | None => (),
+                        _ => return Err(invalid_data("spnego: the server rejected the authentication")),
+                    }
+                    if let Some(mic) = resp.mech_list_mic {
+                        let expected = ntlm_signature(&self.session_key, SERVER_SIGNING, SERVER_SEALING, &mech_type_list());
+                        if mic != &expected[..] {
+                            return Err(invalid_data("spnego: invalid mechListMIC of the server"));
+                        }
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use winauth::{NextBytes, NtlmV2ClientBuilder};
+    use super::*;
+
+    #[test]
+    fn ntowf_v2_vector() {
+        // [MS-NLMP 4.2.4.1.1]
+        let key = ntowf_v2("Domain", "User", "Password");
+        assert_eq!(key, [0x0c, 0x86, 0x8a, 0x40, 0x3b, 0xfd, 0x7a, 0x93, 0xa3, 0x00, 0x1e, 0xf2, 0x2e, 0xf0, 0x2e, 0x3f]);
+    }
+
+    /// a CHALLENGE message [MS-NLMP 2.2.1.2] with the flags the NTLM client requires
+    fn challenge_message() -> Vec<u8> {
+        let mut target_info = vec![];
+        for &(id, ref value) in &[(3u16, ucs2("server")), (7, vec![0; 8]), (0, vec![])] {
+            let mut head = [0u8; 4];
+            LittleEndian::write_u16(&mut head, id);
+            LittleEndian::write_u16(&mut head[2..], value.len() as u16);
+            target_info.extend_from_slice(&head);
+            target_info.extend_from_slice(value);
+        }
+        let mut msg = b"NTLMSSP\0".to_vec();
+        msg.extend_from_slice(&[2, 0, 0, 0]);
+        // an empty target name, the flags, the server challenge and the reserved bytes
+        msg.extend_from_slice(&[0, 0, 0, 0, 0xff, 0, 0, 0]);
+        msg.extend_from_slice(&[0x35, 0x82, 0x89, 0xe0]);
+        msg.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        msg.extend_from_slice(&[0; 8]);
+        let len = target_info.len() as u8;
+        msg.extend_from_slice(&[len, 0, len, 0, 48, 0, 0, 0]);
+        msg.extend(target_info);
+        msg
+    }
+
+    #[test]
+    fn ntlm_in_spnego() {
+        let ntlm = NtlmV2ClientBuilder::new().build(Some("Domain"), "User", "Password");
+        let mut client = NegotiateClient::new(ntlm, "Domain", "User", "Password");
+
+        let init = client.next_bytes(None).unwrap().unwrap();
+        let (tag, content, _) = der_read(&init).unwrap();
+        assert_eq!(tag, DER_APPLICATION);
+        let (_, oid, rest) = der_read(content).unwrap();
+        assert_eq!(oid, SPNEGO_OID);
+
+        // the NEGOTIATE message within NegTokenInit
+        let (_, token, _) = der_read(rest).unwrap();
+        let (_, mut fields, _) = der_read(token).unwrap();
+        let mut negotiate = vec![];
+        while !fields.is_empty() {
+            let (tag, field, rest) = der_read(fields).unwrap();
+            if tag == 0xa2 {
+                negotiate = der_read(field).unwrap().1.to_vec();
+            }
+            fields = rest;
+        }
+        assert_eq!(&negotiate[..8], b"NTLMSSP\0");
+
+        let challenge = challenge_message();
+        let mut resp = der(0xa0, &der(DER_ENUMERATED, &[ACCEPT_INCOMPLETE]));
+        resp.extend(der(0xa1, &der(DER_OID, NTLMSSP_OID)));
+        resp.extend(der(0xa2, &der(DER_OCTET_STRING, &challenge)));
+        let resp = der(0xa1, &der(DER_SEQUENCE, &resp));
+        let auth = client.next_bytes(Some(&resp)).unwrap().unwrap();
+        let auth = NegTokenResp::decode(&auth).unwrap();
+        assert_eq!(&auth.response_token.unwrap()[..8], b"NTLMSSP\0");
+        // the recovered session key matches the one used for the MIC of the AUTHENTICATE message
+        let mut authenticate = auth.response_token.unwrap().to_vec();
+        let ntlm_mic = authenticate[72..88].to_vec();
+        authenticate[72..88].copy_from_slice(&[0; 16]);
+        let content = [&negotiate[..], &challenge, &authenticate].concat();
+        assert_eq!(&hmac_md5(&client.session_key, &content)[..], &ntlm_mic[..]);
+
+        let mic = auth.mech_list_mic.unwrap();
+        assert_eq!(mic.len(), 16);
+        assert_eq!(&mic[..4], &[1, 0, 0, 0]);
+
+        // the server confirms with its own mechListMIC
+        let server_mic = ntlm_signature(&client.session_key, SERVER_SIGNING, SERVER_SEALING, &mech_type_list());
+        let mut done = der(0xa0, &der(DER_ENUMERATED, &[ACCEPT_COMPLETED]));
+        done.extend(der(0xa3, &der(DER_OCTET_STRING, &server_mic)));
+        let done = der(0xa1, &der(DER_SEQUENCE, &done));
+        assert!(client.next_bytes(Some(&done)).unwrap().is_none());
+    }
+
+    #[test]
+    fn spnego_reject() {
+        let ntlm = NtlmV2ClientBuilder::new().build(None::<&str>, "User", "Password");
+        let mut client = NegotiateClient::new(ntlm, "", "User", "Password");
+        client.next_bytes(None).unwrap();
+        let reject = der(0xa1, &der(DER_SEQUENCE, &der(0xa0, &der(DER_ENUMERATED, &[REJECT]))));
+        assert!(client.next_bytes(Some(&reject)).is_err());
+    }
+}
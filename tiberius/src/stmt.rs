@@ -1,14 +1,16 @@
 //! Prepared statements
 use std::borrow::Cow;
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
-use futures::{Async, Future, Poll, Sink, Stream};
+use futures::{Async, Future, Poll, Stream};
 use futures::sync::oneshot;
 use futures_state_stream::{StateStream, StreamEvent};
+use tokio::timer::Delay;
 use query::{ExecFuture, QueryStream};
 use tokens::{DoneStatus, TdsResponseToken, TokenColMetaData};
 use types::{ColumnData, ToSql};
-use {BoxableIo, SqlConnection, StmtResult, Error};
+use {BoxableIo, CancelHandle, Cancelable, SqlConnection, StmtResult, Error};
 
 /// A prepared statement which is prepared on the first execution
 /// (which is a technical requirement since you need to know the types)
@@ -63,6 +65,9 @@ pub struct StmtStream<I: BoxableIo, R: StmtResult<I>> {
     conn: Option<SqlConnection<I>>,
     param_sig: Option<Vec<&'static str>>,
     receiver: Option<oneshot::Receiver<SqlConnection<I>>>,
+    cancel: CancelHandle,
+    /// the time the server may take to finish the response, `None` once the deadline was set on the first poll
+    timeout: Option<Option<Duration>>,
     stmt: Statement,
     meta: Option<Arc<TokenColMetaData>>,
 
@@ -84,6 +89,8 @@ impl<I: BoxableIo, R: StmtResult<I>> StmtStream<I, R> {
         StmtStream {
             err: None,
             done: false,
+            cancel: CancelHandle(conn.0.transport.cancel.clone()),
+            timeout: Some(conn.0.params.command_timeout),
             conn: Some(conn),
             param_sig: Some(signature),
             receiver: None,
@@ -100,6 +107,18 @@ impl<I: BoxableIo, R: StmtResult<I>> StmtStream<I, R> {
     }
 }
 
+impl<I: BoxableIo, R: StmtResult<I>> Cancelable for StmtStream<I, R> {
+    fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if self.timeout.is_some() {
+            self.timeout = Some(timeout);
+        }
+    }
+}
+
 impl<I: BoxableIo, R: StmtResult<I>> StateStream for StmtStream<I, R> {
     type Item = R::Result;
     type State = SqlConnection<I>;
@@ -123,10 +142,15 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for StmtStream<I, R> {
             self.receiver = None;
         }
 
+        if let Some(timeout) = self.timeout.take() {
+            let transport = &mut self.conn.as_mut().unwrap().0.transport;
+            transport.deadline = timeout.map(|timeout| Delay::new(Instant::now() + timeout));
+        }
+
         try_ready!(
             self.conn
                 .as_mut()
-                .map(|x| x.0.transport.inner.poll_complete())
+                .map(|x| x.0.poll_send())
                 .unwrap()
         );
 
@@ -160,6 +184,13 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for StmtStream<I, R> {
                     self.done = true;
                     (!old, !old) //reinject if !old, see below
                 }
+                // the request was canceled, this acknowledges the attention signal instead of DoneProc
+                TdsResponseToken::Done(ref done) if done.status.contains(DoneStatus::ATTENTION) => {
+                    let old = self.already_triggered;
+                    self.already_triggered = false;
+                    self.done = true;
+                    (!old, !old)
+                }
                 // this simply notifies us that a DoneProc is following (DONE_MORE)
                 TdsResponseToken::DoneInProc(_) => (false, false),
                 TdsResponseToken::ReturnStatus(ref status) => {
@@ -200,12 +231,32 @@ impl<I: BoxableIo, R: StmtResult<I>> StateStream for StmtStream<I, R> {
             }
         }
 
+        // the connection is returned as the state when polled again after a timeout
+        if self.conn.as_mut().unwrap().0.transport.take_timed_out() {
+            return Err(Error::Timeout);
+        }
         // this stream is done, make sure it cannot be executed again
         let conn = self.conn.take().unwrap();
         Ok(Async::Ready(StreamEvent::Done(conn)))
     }
 }
 
+/// Poll the stream of a request, keeping the connection it returns as its state once it failed with `Error::Timeout`
+fn poll_timed_out<S>(stream: &mut S, conn: &mut Option<S::State>) -> Poll<StreamEvent<S::Item, S::State>, Error>
+where
+    S: StateStream<Error = Error>,
+{
+    match stream.poll() {
+        Err(Error::Timeout) => {
+            if let Ok(Async::Ready(StreamEvent::Done(state))) = stream.poll() {
+                *conn = Some(state);
+            }
+            Err(Error::Timeout)
+        }
+        ret => ret,
+    }
+}
+
 /// A single resultset yielding the status of query execution
 /// (currently the amount of affected rows)
 #[must_use = "futures do nothing unless polled"]
@@ -214,6 +265,8 @@ pub struct ExecResult<S: StateStream> where S::Item: Future {
     idx: usize,
     resultset: Option<S::Item>,
     result: Option<<S::Item as Future>::Item>,
+    /// the connection, once the request timed out
+    conn: Option<S::State>,
 }
 
 impl<S: StateStream> ExecResult<S> where S::Item: Future
@@ -224,6 +277,7 @@ impl<S: StateStream> ExecResult<S> where S::Item: Future
             idx: 0,
             resultset: None,
             result: None,
+            conn: None,
         }
     }
 
@@ -231,6 +285,30 @@ impl<S: StateStream> ExecResult<S> where S::Item: Future
     pub fn into_stream(self) -> S {
         self.stream
     }
+
+    /// A handle to cancel the running request
+    pub fn cancel_handle(&self) -> CancelHandle
+    where
+        S: Cancelable,
+    {
+        self.stream.cancel_handle()
+    }
+
+    /// Cancel the request and fail with `Error::Timeout` if the server did not finish the response
+    /// within `timeout`, replacing the connection's `command_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self
+    where
+        S: Cancelable,
+    {
+        self.stream.set_timeout(Some(timeout));
+        self
+    }
+
+    /// The connection once the future failed with `Error::Timeout`, which is usable again
+    /// (e.g. `rt.block_on(&mut exec)` followed by `exec.into_connection()`)
+    pub fn into_connection(self) -> Option<S::State> {
+        self.conn
+    }
 }
 
 impl<I, S> Future for ExecResult<S>
@@ -248,7 +326,7 @@ where
             }
             // ensure we do not poll the same resultset again
             self.resultset = None;
-            self.resultset = match try_ready!(self.stream.poll()) {
+            self.resultset = match try_ready!(poll_timed_out(&mut self.stream, &mut self.conn)) {
                 StreamEvent::Next(resultset) => Some(resultset),
                 StreamEvent::Done(conn) => {
                     let result = self.result
@@ -271,6 +349,8 @@ pub struct QueryResult<S: StateStream> {
     stream: S,
     idx: usize,
     resultset: Option<S::Item>,
+    /// the connection, once the request timed out
+    conn: Option<S::State>,
 }
 
 impl<S: StateStream> QueryResult<S>
@@ -280,6 +360,7 @@ impl<S: StateStream> QueryResult<S>
             stream,
             idx: 0,
             resultset: None,
+            conn: None,
         }
     }
 
@@ -287,6 +368,30 @@ impl<S: StateStream> QueryResult<S>
     pub fn into_stream(self) -> S {
         self.stream
     }
+
+    /// A handle to cancel the running request
+    pub fn cancel_handle(&self) -> CancelHandle
+    where
+        S: Cancelable,
+    {
+        self.stream.cancel_handle()
+    }
+
+    /// Cancel the request and fail with `Error::Timeout` if the server did not finish the response
+    /// within `timeout`, replacing the connection's `command_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self
+    where
+        S: Cancelable,
+    {
+        self.stream.set_timeout(Some(timeout));
+        self
+    }
+
+    /// The connection once the stream failed with `Error::Timeout`, which is usable again.
+    /// Polling the stream again returns it as the state too.
+    pub fn into_connection(self) -> Option<S::State> {
+        self.conn
+    }
 }
 
 impl<I, S> StateStream for QueryResult<S> 
@@ -299,6 +404,9 @@ where
     type Error = <QueryStream<I> as Stream>::Error;
 
     fn poll(&mut self) -> Poll<StreamEvent<Self::Item, Self::State>, Self::Error> {
+        if let Some(conn) = self.conn.take() {
+            return Ok(Async::Ready(StreamEvent::Done(conn)));
+        }
         loop {
             if let Some(ref mut resultset) = self.resultset {
                 if let Some(result) = try_ready!(resultset.poll()) {
@@ -308,7 +416,7 @@ where
             
             // ensure we do not poll the same resultset again
             self.resultset = None;
-            self.resultset = match try_ready!(self.stream.poll()) {
+            self.resultset = match try_ready!(poll_timed_out(&mut self.stream, &mut self.conn)) {
                 StreamEvent::Next(resultset) => Some(resultset),
                 StreamEvent::Done(conn) => return Ok(Async::Ready(StreamEvent::Done(conn))),
             };
@@ -2,11 +2,11 @@
 use std::borrow::Cow;
 use std::sync::Arc;
 use bytes::Bytes;
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use futures::{Async, Poll};
 use transport::{Io, NoLength, PrimitiveWrites, ReadState, Str, TdsTransport};
 use types::{ColumnData, TypeInfo};
-use protocol::{self, FeatureLevel, PacketHeader, PacketStatus, PacketType, PacketWriter};
+use protocol::{self, FeatureId, FeatureLevel, PacketHeader, PacketStatus, PacketType, PacketWriter};
 use {FromUint, Error, Result};
 
 /// read a token from an underlying transport
@@ -27,10 +27,13 @@ uint_enum! {
         Order = 0xA9,
         ReturnValue = 0xAC,
         LoginAck = 0xAD,
+        FeatureExtAck = 0xAE,
         Row = 0xD1,
         NbcRow = 0xD2,
         SSPI = 0xED,
         EnvChange = 0xE3,
+        SessionState = 0xE4,
+        FedAuthInfo = 0xEE,
         Done = 0xFD,
         DoneProc = 0xFE,
         DoneInProc = 0xFF,
@@ -45,6 +48,9 @@ pub enum TdsResponseToken {
     Info(TokenInfo),
     Order(TokenOrder),
     LoginAck(TokenLoginAck),
+    FeatureExtAck(TokenFeatureExtAck),
+    FedAuthInfo(::FedAuthInfo),
+    SessionState(TokenSessionState),
     Done(TokenDone),
     ColMetaData(Arc<TokenColMetaData>),
     Row(TokenRow),
@@ -69,10 +75,31 @@ impl<I: Io> TdsTransport<I> {
                 Ok(Async::NotReady)
             }
             Tokens::ColMetaData => TokenColMetaData::parse_token(self),
-            Tokens::EnvChange => TokenEnvChange::parse_token(self),
+            Tokens::EnvChange => {
+                if let Some(bytes) = self.inner.read_bytes(min_len) {
+                    let token = TokenEnvChange::parse(bytes)?;
+                    return Ok(Async::Ready(TdsResponseToken::EnvChange(token)));
+                }
+                Ok(Async::NotReady)
+            }
             Tokens::Info => TokenInfo::parse_token(self),
             Tokens::Order => TokenOrder::parse_token(self),
             Tokens::LoginAck => TokenLoginAck::parse_token(self),
+            Tokens::FeatureExtAck => TokenFeatureExtAck::parse_token(self),
+            Tokens::FedAuthInfo => {
+                if let Some(bytes) = self.inner.read_bytes(min_len) {
+                    let info = parse_fed_auth_info(&bytes)?;
+                    return Ok(Async::Ready(TdsResponseToken::FedAuthInfo(info)));
+                }
+                Ok(Async::NotReady)
+            }
+            Tokens::SessionState => {
+                if let Some(bytes) = self.inner.read_bytes(min_len) {
+                    let token = TokenSessionState::parse(bytes)?;
+                    return Ok(Async::Ready(TdsResponseToken::SessionState(token)));
+                }
+                Ok(Async::NotReady)
+            }
             Tokens::Done => TokenDone::parse_token(self),
             Tokens::DoneProc => TokenDoneProc::parse_token(self),
             Tokens::DoneInProc => TokenDoneInProc::parse_token(self),
@@ -90,12 +117,33 @@ impl<I: Io> TdsTransport<I> {
 
 #[derive(Debug)]
 pub enum TokenEnvChange {
-    Database(Str, Str),
+    Database(String, String),
+    Language(String, String),
+    CharacterSet(String, String),
     PacketSize(u32, u32),
+    /// The locale id used for sorting unicode data
+    UnicodeSortingLocale(String),
+    /// The flags used for comparing unicode data
+    UnicodeComparisonFlags(String),
     SqlCollation(Bytes, Bytes),
     BeginTransaction(u64),
     RollbackTransaction(u64),
     CommitTransaction(u64),
+    EnlistDTCTransaction(u64),
+    DefectTransaction(u64),
+    /// The failover partner of a mirrored database
+    MirroringPartner(String),
+    /// The DTC token of a local transaction promoted to a distributed transaction
+    PromoteTransaction(Bytes),
+    TransactionManagerAddress(Bytes),
+    TransactionEnded(u64),
+    /// The acknowledgement of a connection reset requested by the client
+    ResetConnection,
+    UserName(String),
+    /// The alternate server (which may contain an instance name) and the port to log in to instead
+    Routing(String, u16),
+    /// A type this implementation does not know about
+    Unknown(u8),
 }
 
 uint_enum! {
@@ -114,6 +162,7 @@ uint_enum! {
         RollbackTransaction = 10,
         EnlistDTCTransaction = 11,
         DefectTransaction = 12,
+        /// database mirroring partner (real time log shipping)
         RTLS = 13,
         PromoteTransaction = 15,
         TransactionManagerAddress = 16,
@@ -125,49 +174,128 @@ uint_enum! {
     }
 }
 
-impl<I: Io> ParseToken<I> for TokenEnvChange {
-    fn parse_token(trans: &mut TdsTransport<I>) -> Poll<TdsResponseToken, Error> {
-        let ty = trans.inner.read_u8()?;
-        let token = match EnvChangeTy::from_u8(ty) {
-            Some(EnvChangeTy::Database) => {
-                let new_value = try_ready!(trans.inner.read_varchar::<u8>(false));
-                let old_value = try_ready!(trans.inner.read_varchar::<u8>(false));
-                TokenEnvChange::Database(new_value, old_value)
-            }
-            Some(EnvChangeTy::PacketSize) => {
-                let new_value = try_ready!(trans.inner.read_varchar::<u8>(false));
-                let old_value = try_ready!(trans.inner.read_varchar::<u8>(false));
-                let new_size = new_value.as_str().parse::<u32>()?;
-                let old_size = old_value.as_str().parse::<u32>()?;
-                TokenEnvChange::PacketSize(new_size, old_size)
-            }
-            Some(EnvChangeTy::SqlCollation) => {
-                let new_value = try_ready!(trans.inner.read_varbyte::<u8>());
-                let old_value = try_ready!(trans.inner.read_varbyte::<u8>());
-                TokenEnvChange::SqlCollation(new_value, old_value)
+/// reads the values of an ENVCHANGE token, which are all contained in `data`
+struct EnvChangeReader {
+    data: Bytes,
+    pos: usize,
+}
+
+impl EnvChangeReader {
+    fn bytes(&mut self, len: usize) -> Result<Bytes> {
+        if self.data.len() - self.pos < len {
+            return Err(Error::Protocol("envchange: value exceeds the token length".into()));
+        }
+        self.pos += len;
+        Ok(self.data.slice(self.pos - len, self.pos))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(LittleEndian::read_u16(&self.bytes(2)?))
+    }
+
+    fn b_varbyte(&mut self) -> Result<Bytes> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    fn l_varbyte(&mut self) -> Result<Bytes> {
+        let len = LittleEndian::read_u32(&self.bytes(4)?) as usize;
+        self.bytes(len)
+    }
+
+    /// a string with its length in characters given as `len`
+    fn ucs2(&mut self, len: usize) -> Result<String> {
+        let bytes = self.bytes(len * 2)?;
+        let data: Vec<u16> = bytes.chunks(2).map(LittleEndian::read_u16).collect();
+        Ok(String::from_utf16(&data)?)
+    }
+
+    fn b_varchar(&mut self) -> Result<String> {
+        let len = self.u8()? as usize;
+        self.ucs2(len)
+    }
+
+    fn us_varchar(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        self.ucs2(len)
+    }
+
+    /// a transaction descriptor
+    fn transaction(&mut self) -> Result<u64> {
+        let value = self.b_varbyte()?;
+        if value.len() != 8 {
+            return Err(Error::Protocol("envchange: invalid transaction descriptor".into()));
+        }
+        Ok(LittleEndian::read_u64(&value))
+    }
+
+    fn packet_size(&mut self) -> Result<u32> {
+        self.b_varchar()?
+            .parse()
+            .map_err(|_| Error::Protocol("envchange: invalid packet size".into()))
+    }
+}
+
+impl TokenEnvChange {
+    /// parse the data of an ENVCHANGE token, unknown types are skipped
+    pub fn parse(data: Bytes) -> Result<TokenEnvChange> {
+        let mut rd = EnvChangeReader { data, pos: 0 };
+        let ty = rd.u8()?;
+        let ty = match EnvChangeTy::from_u8(ty) {
+            Some(ty) => ty,
+            None => return Ok(TokenEnvChange::Unknown(ty)),
+        };
+        // the values which are always empty are not checked
+        let token = match ty {
+            EnvChangeTy::Database => TokenEnvChange::Database(rd.b_varchar()?, rd.b_varchar()?),
+            EnvChangeTy::Language => TokenEnvChange::Language(rd.b_varchar()?, rd.b_varchar()?),
+            EnvChangeTy::CharacterSet => TokenEnvChange::CharacterSet(rd.b_varchar()?, rd.b_varchar()?),
+            EnvChangeTy::PacketSize => TokenEnvChange::PacketSize(rd.packet_size()?, rd.packet_size()?),
+            EnvChangeTy::UnicodeDataSortingLID => TokenEnvChange::UnicodeSortingLocale(rd.b_varchar()?),
+            EnvChangeTy::UnicodeDataSortingCFL => TokenEnvChange::UnicodeComparisonFlags(rd.b_varchar()?),
+            EnvChangeTy::SqlCollation => TokenEnvChange::SqlCollation(rd.b_varbyte()?, rd.b_varbyte()?),
+            EnvChangeTy::BeginTransaction => TokenEnvChange::BeginTransaction(rd.transaction()?),
+            EnvChangeTy::DefectTransaction => TokenEnvChange::DefectTransaction(rd.transaction()?),
+            EnvChangeTy::CommitTransaction |
+            EnvChangeTy::RollbackTransaction |
+            EnvChangeTy::EnlistDTCTransaction |
+            EnvChangeTy::TransactionEnded => {
+                // the new value is empty, the old value is the descriptor of the transaction
+                rd.b_varbyte()?;
+                let old_value = rd.transaction()?;
+                match ty {
+                    EnvChangeTy::CommitTransaction => TokenEnvChange::CommitTransaction(old_value),
+                    EnvChangeTy::RollbackTransaction => TokenEnvChange::RollbackTransaction(old_value),
+                    EnvChangeTy::EnlistDTCTransaction => TokenEnvChange::EnlistDTCTransaction(old_value),
+                    _ => TokenEnvChange::TransactionEnded(old_value),
+                }
             }
-            Some(EnvChangeTy::BeginTransaction) => {
-                assert_eq!(trans.inner.read_u8()?, 8);
-                let new_value = trans.inner.read_u64::<LittleEndian>()?;
-                let old_value = trans.inner.read_u8()?;
-                assert_eq!(old_value, 0);
-                TokenEnvChange::BeginTransaction(new_value)
+            EnvChangeTy::RTLS => TokenEnvChange::MirroringPartner(rd.b_varchar()?),
+            EnvChangeTy::PromoteTransaction => TokenEnvChange::PromoteTransaction(rd.l_varbyte()?),
+            EnvChangeTy::TransactionManagerAddress => {
+                TokenEnvChange::TransactionManagerAddress(rd.b_varbyte()?)
             }
-            Some(ty @ EnvChangeTy::RollbackTransaction) |
-            Some(ty @ EnvChangeTy::CommitTransaction) => {
-                let new_value = trans.inner.read_u8()?;
-                assert_eq!(new_value, 0);
-                assert_eq!(trans.inner.read_u8()?, 8);
-                let old_value = trans.inner.read_u64::<LittleEndian>()?;
-                if let EnvChangeTy::RollbackTransaction = ty {
-                    TokenEnvChange::RollbackTransaction(old_value)
-                } else {
-                    TokenEnvChange::CommitTransaction(old_value)
+            EnvChangeTy::ResetConnection => TokenEnvChange::ResetConnection,
+            EnvChangeTy::UserName => TokenEnvChange::UserName(rd.b_varchar()?),
+            EnvChangeTy::Routing => {
+                // the length of the routing data, the protocol (TCP),
+                // the protocol property (port) and the alternate server
+                rd.u16()?;
+                let protocol = rd.u8()?;
+                if protocol != 0 {
+                    return Err(Error::Protocol(
+                        format!("routing: unsupported protocol {}", protocol).into(),
+                    ));
                 }
+                let port = rd.u16()?;
+                TokenEnvChange::Routing(rd.us_varchar()?, port)
             }
-            _ => panic!("unimplemented env change ty: {:x}", ty),
         };
-        Ok(Async::Ready(TdsResponseToken::EnvChange(token)))
+        Ok(token)
     }
 }
 
@@ -221,11 +349,11 @@ pub struct TokenLoginAck {
     /// 0: SQL_DFLT (server confirms that whatever is sent by the client is acceptable. If the client
     ///    requested SQL_DFLT, SQL_TSQL will be used)
     /// 1: SQL_TSQL (TSQL is accepted)
-    interface: u8,
-    tds_version: FeatureLevel,
-    prog_name: Str,
+    pub interface: u8,
+    pub tds_version: FeatureLevel,
+    pub prog_name: Str,
     /// major.minor.buildhigh.buildlow
-    version: u32,
+    pub version: u32,
 }
 
 impl<I: Io> ParseToken<I> for TokenLoginAck {
@@ -241,6 +369,134 @@ impl<I: Io> ParseToken<I> for TokenLoginAck {
     }
 }
 
+/// The acknowledgement of a feature the client requested in the FeatureExt of the login [2.2.7.11]
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeatureAck {
+    /// the initial state of the session, which is the base for recovering it
+    SessionRecovery(Vec<(u8, Bytes)>),
+    /// the nonce and signature of the server (only for the security token library)
+    FedAuth(Bytes),
+    /// the options the server supports
+    AzureSqlSupport(u8),
+    Utf8Support(bool),
+    Unknown(u8, Bytes),
+}
+
+impl FeatureAck {
+    pub fn parse(feature_id: u8, data: Bytes) -> Result<FeatureAck> {
+        let byte = |i: usize| {
+            data.get(i)
+                .cloned()
+                .ok_or_else(|| Error::Protocol(format!("featureextack: feature {:#x} is too short", feature_id).into()))
+        };
+        Ok(match FeatureId::from_u8(feature_id) {
+            Some(FeatureId::SessionRecovery) => FeatureAck::SessionRecovery(parse_session_states(data)?),
+            Some(FeatureId::FedAuth) => FeatureAck::FedAuth(data),
+            Some(FeatureId::AzureSqlSupport) => FeatureAck::AzureSqlSupport(byte(0)?),
+            Some(FeatureId::Utf8Support) => FeatureAck::Utf8Support(byte(0)? & 1 == 1),
+            _ => FeatureAck::Unknown(feature_id, data),
+        })
+    }
+}
+
+/// The acknowledgements of the features the client requested in the FeatureExt of the login
+#[derive(Debug)]
+pub struct TokenFeatureExtAck {
+    pub features: Vec<FeatureAck>,
+}
+
+impl<I: Io> ParseToken<I> for TokenFeatureExtAck {
+    fn parse_token(trans: &mut TdsTransport<I>) -> Poll<TdsResponseToken, Error> {
+        let mut features = vec![];
+        loop {
+            let feature_id = trans.inner.read_u8()?;
+            if feature_id == 0xFF {
+                break;
+            }
+            let len = trans.inner.read_u32::<LittleEndian>()? as usize;
+            match trans.inner.read_bytes(len) {
+                Some(data) => features.push(FeatureAck::parse(feature_id, data)?),
+                None => return Ok(Async::NotReady),
+            }
+        }
+        Ok(Async::Ready(TdsResponseToken::FeatureExtAck(TokenFeatureExtAck { features })))
+    }
+}
+
+/// [2.2.7.21] parse a SessionStateDataSet: the id and the value of every state
+fn parse_session_states(data: Bytes) -> Result<Vec<(u8, Bytes)>> {
+    let invalid = || Error::Protocol("sessionstate: invalid state data".into());
+    let (mut states, mut pos) = (vec![], 0);
+    while pos < data.len() {
+        let id = data[pos];
+        let (len, start) = match data.get(pos + 1) {
+            Some(&0xff) => {
+                let len = data.get(pos + 2..pos + 6).ok_or_else(invalid)?;
+                (LittleEndian::read_u32(len) as usize, pos + 6)
+            }
+            Some(&len) => (len as usize, pos + 2),
+            None => return Err(invalid()),
+        };
+        if start + len > data.len() {
+            return Err(invalid());
+        }
+        states.push((id, data.slice(start, start + len)));
+        pos = start + len;
+    }
+    Ok(states)
+}
+
+/// The changes of the state of the session, which allow to recover it (SESSIONRECOVERY)
+#[derive(Debug)]
+pub struct TokenSessionState {
+    pub seq_no: u32,
+    /// whether the session can be recovered using the changed states
+    pub recoverable: bool,
+    pub states: Vec<(u8, Bytes)>,
+}
+
+impl TokenSessionState {
+    /// [2.2.7.21] parse the token (without its length)
+    pub fn parse(data: Bytes) -> Result<TokenSessionState> {
+        if data.len() < 5 {
+            return Err(Error::Protocol("sessionstate: token is too short".into()));
+        }
+        Ok(TokenSessionState {
+            seq_no: LittleEndian::read_u32(&data),
+            recoverable: data[4] & 0x01 == 0x01,
+            states: parse_session_states(data.slice_from(5))?,
+        })
+    }
+}
+
+/// [2.2.7.12] parse the FEDAUTHINFO token (without its length)
+fn parse_fed_auth_info(data: &[u8]) -> Result<::FedAuthInfo> {
+    let invalid = || Error::Protocol("fedauthinfo: invalid token".into());
+    if data.len() < 4 {
+        return Err(invalid());
+    }
+    let count = LittleEndian::read_u32(data) as usize;
+    let mut info = ::FedAuthInfo {
+        sts_url: String::new(),
+        spn: String::new(),
+    };
+    for i in 0..count {
+        // FedAuthInfoID, FedAuthInfoDataLen, FedAuthInfoDataOffset (relative to the count)
+        let opt = data.get(4 + i * 9..4 + (i + 1) * 9).ok_or_else(invalid)?;
+        let len = LittleEndian::read_u32(&opt[1..]) as usize;
+        let offset = LittleEndian::read_u32(&opt[5..]) as usize;
+        let value = data.get(offset..offset + len).ok_or_else(invalid)?;
+        let value: Vec<u16> = value.chunks(2).map(LittleEndian::read_u16).collect();
+        let value = String::from_utf16(&value)?;
+        match opt[0] {
+            0x01 => info.sts_url = value,
+            0x02 => info.spn = value,
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
 bitflags! {
     pub struct DoneStatus: u16 {
         const MORE = 0x1;
@@ -615,6 +871,7 @@ impl<'a, I: Io> WriteToken<I> for TokenRpcRequest<'a> {
             status: PacketStatus::NormalMessage,
             ..PacketHeader::new(0, 0)
         };
+        trans.start_request();
         let mut writer = PacketWriter::new(&mut trans.inner, header);
 
         protocol::write_trans_descriptor(&mut writer, trans.transaction)?;
@@ -648,3 +905,128 @@ impl<'a, I: Io> WriteToken<I> for TokenRpcRequest<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use super::{FeatureAck, TokenEnvChange, TokenSessionState};
+    use Error;
+
+    fn b_varchar(s: &str) -> Vec<u8> {
+        let chars: Vec<u16> = s.encode_utf16().collect();
+        let mut ret = vec![chars.len() as u8];
+        for c in chars {
+            ret.extend_from_slice(&[c as u8, (c >> 8) as u8]);
+        }
+        ret
+    }
+
+    fn parse(data: Vec<u8>) -> ::Result<TokenEnvChange> {
+        TokenEnvChange::parse(Bytes::from(data))
+    }
+
+    #[test]
+    fn env_change_types() {
+        let mut data = vec![2];
+        data.extend(b_varchar("Deutsch"));
+        data.extend(b_varchar("us_english"));
+        match parse(data).unwrap() {
+            TokenEnvChange::Language(ref new, ref old) => {
+                assert_eq!(new, "Deutsch");
+                assert_eq!(old, "us_english");
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+
+        let mut data = vec![4];
+        data.extend(b_varchar("8192"));
+        data.extend(b_varchar("4096"));
+        match parse(data).unwrap() {
+            TokenEnvChange::PacketSize(8192, 4096) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        let data = vec![17, 0, 8, 1, 0, 0, 0, 0, 0, 0, 0];
+        match parse(data).unwrap() {
+            TokenEnvChange::TransactionEnded(1) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        let mut data = vec![19];
+        data.extend(b_varchar("sa"));
+        data.push(0);
+        match parse(data).unwrap() {
+            TokenEnvChange::UserName(ref name) => assert_eq!(name, "sa"),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        match parse(vec![18, 0, 0]).unwrap() {
+            TokenEnvChange::ResetConnection => (),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        match parse(vec![14, 1, 2, 3]).unwrap() {
+            TokenEnvChange::Unknown(14) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn env_change_malformed() {
+        // a transaction descriptor of the wrong length
+        match parse(vec![8, 4, 1, 0, 0, 0, 0]) {
+            Err(Error::Protocol(_)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+        // a string exceeding the token
+        match parse(vec![1, 10, b'a', 0]) {
+            Err(Error::Protocol(_)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+        // a packet size which is not a number
+        let mut data = vec![4];
+        data.extend(b_varchar("big"));
+        data.extend(b_varchar("4096"));
+        match parse(data) {
+            Err(Error::Protocol(_)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn feature_ack_types() {
+        let ack = |id: u8, data: &[u8]| FeatureAck::parse(id, Bytes::from(data.to_vec()));
+        assert_eq!(ack(0x0a, &[1]).unwrap(), FeatureAck::Utf8Support(true));
+        assert_eq!(ack(0x0a, &[0]).unwrap(), FeatureAck::Utf8Support(false));
+        assert_eq!(ack(0x08, &[0]).unwrap(), FeatureAck::AzureSqlSupport(0));
+        assert_eq!(
+            ack(0x01, &[1, 1, 2]).unwrap(),
+            FeatureAck::SessionRecovery(vec![(1, Bytes::from(&[2][..]))])
+        );
+        assert_eq!(ack(0x42, &[7]).unwrap(), FeatureAck::Unknown(0x42, Bytes::from(&[7][..])));
+        match ack(0x08, &[]) {
+            Err(Error::Protocol(_)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn session_state() {
+        // a short and a long (0xFF followed by a DWORD) state
+        let mut data = vec![3, 0, 0, 0, 1, 0x02, 1, 7, 0x09, 0xff, 0, 1, 0, 0];
+        data.extend(vec![5; 256]);
+        let token = TokenSessionState::parse(Bytes::from(data)).unwrap();
+        assert_eq!(token.seq_no, 3);
+        assert!(token.recoverable);
+        assert_eq!(token.states.len(), 2);
+        assert_eq!(token.states[0], (0x02, Bytes::from(&[7][..])));
+        assert_eq!(token.states[1].0, 0x09);
+        assert_eq!(token.states[1].1.len(), 256);
+
+        // a state exceeding the token
+        match TokenSessionState::parse(Bytes::from(&[3, 0, 0, 0, 0, 0x02, 2, 7][..])) {
+            Err(Error::Protocol(_)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+}
@@ -1,29 +1,32 @@
 //! low level transport that deals with reading bytes from an underlying Io
 //! handling data split accross packets, etc.
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
 use std::io::{self, Cursor, Write};
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::str;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
 use bytes::{BufMut, Bytes, BytesMut};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use futures::{Async, Poll, Sink, StartSend};
-use protocol::{self, PacketHeader, PacketStatus};
+use futures::{Async, Future, Poll, Sink, StartSend};
+use futures::task::AtomicTask;
+use protocol::{self, PacketHeader, PacketStatus, PacketType};
 use plp::{ReadTyMode, ReadTyState};
-use tokens::{TdsResponseToken, TokenColMetaData, TokenEnvChange, Tokens};
-use types::ColumnData;
-use {FromUint, Error};
+use tokens::{DoneStatus, FeatureAck, TdsResponseToken, TokenColMetaData, TokenEnvChange, Tokens};
+use types::{Collation, ColumnData};
+use {FromUint, Error, ServerInfo, ServerVersion};
 
 pub trait Io: AsyncRead + AsyncWrite {}
 impl<I: AsyncRead + AsyncWrite> Io for I {}
 
-#[cfg(feature = "tls")]
+#[cfg(any(feature = "tls", feature = "rustls"))]
 pub mod tls {
-    extern crate native_tls;
-    extern crate tokio_tls;
+    extern crate sha2;
 
     use std::cmp;
     use std::io::{self, Read, Write};
@@ -31,15 +34,17 @@ pub mod tls {
     use tokio::io::{AsyncRead, AsyncWrite};
     use protocol::{self, PacketHeader, PacketStatus, PacketType};
     use transport::Io;
-    pub use self::tokio_tls::{Connect, TlsStream};
-    use Error;
+    use self::sha2::{Digest, Sha256};
+    use {ConnectParams, Error, Result, TlsVersion};
 
-    impl From<native_tls::Error> for Error {
-        fn from(e: native_tls::Error) -> Error {
-            let err = format!("{:?}", e);
-            Error::Protocol(err.into())
-        }
-    }
+    #[cfg(all(feature = "tls", not(feature = "rustls")))]
+    pub use self::native::*;
+    // rustls is used if both backends are enabled
+    #[cfg(feature = "rustls")]
+    pub use self::rustls_backend::*;
+
+    /// The ALPN protocol of TDS 8.0 (strict encryption)
+    const TDS_8_ALPN: &str = "tds/8.0";
 
     /// wraps written/read data into PRELOGIN packets
     pub struct TlsTdsWrapper<S> {
@@ -67,6 +72,8 @@ pub mod tls {
         #[inline]
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
             if !self.wrap {
+                // the end of the handshake (e.g. the Finished message of TLS 1.3) may still be buffered
+                self.flush_wrapped()?;
                 self.stream.write(buf)
             } else {
                 self.wr.extend_from_slice(buf);
@@ -76,10 +83,15 @@ pub mod tls {
 
         #[inline]
         fn flush(&mut self) -> io::Result<()> {
-            if self.wrap {
-                if self.wr.is_empty() {
-                    return Ok(());
-                }
+            self.flush_wrapped()?;
+            self.stream.flush()
+        }
+    }
+
+    impl<S: Io> TlsTdsWrapper<S> {
+        /// send the buffered handshake data as a prelogin packet
+        fn flush_wrapped(&mut self) -> io::Result<()> {
+            if !self.wr.is_empty() {
                 let header = PacketHeader {
                     ty: PacketType::PreLogin,
                     status: PacketStatus::EndOfMessage,
@@ -91,16 +103,23 @@ pub mod tls {
                 self.stream.write_all(&self.wr)?;
                 self.wr.truncate(0);
             }
-            self.stream.flush()
+            Ok(())
         }
     }
 
     impl<S: Io> Read for TlsTdsWrapper<S> {
-        #[inline]
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            if !self.wrap {
-                return self.stream.read(buf);
+            if self.wrap {
+                self.read_wrapped(buf)
+            } else {
+                self.stream.read(buf)
             }
+        }
+    }
+
+    impl<S: Io> TlsTdsWrapper<S> {
+        /// read the data of prelogin packets
+        fn read_wrapped(&mut self, buf: &mut [u8]) -> io::Result<usize> {
 
             // read a new packet header, when required
             if self.bytes_left == 0 {
@@ -152,16 +171,19 @@ pub mod tls {
 
     impl<S: Io> TransportStream<S> {
         pub fn channel_bindings(&self) -> io::Result<Option<Vec<u8>>> {
-            let bytes: Option<&[u8]> = match *self {
-                // TODO: not landed and not working properly yet
-                #[cfg(all(windows, feature = "channel_bindings"))]
-                TransportStream::TLS(ref stream) => {
-                    use transport::tls::native_tls::backend::schannel::TlsStreamExt;
-                    Some(stream.get_ref().raw_stream().get_finish()?)
-                }
+            match *self {
+                TransportStream::TLS(ref stream) => channel_bindings(stream),
+                _ => Ok(None),
+            }
+        }
+
+        /// The TLS version, if TLS is used (or was used for the login)
+        pub fn tls_version(&self) -> Option<TlsVersion> {
+            match *self {
+                TransportStream::TLS(ref stream) |
+                TransportStream::TLSRaw(ref stream) => protocol_version(stream),
                 _ => None,
-            };
-            Ok(bytes.map(|x| [b"tls-unique:" as &[u8], x].concat()))
+            }
         }
     }
 
@@ -172,7 +194,7 @@ pub mod tls {
                 TransportStream::None => unreachable!(),
                 TransportStream::Raw(ref mut raw) => raw.write(buf),
                 TransportStream::TLS(ref mut tls) => tls.write(buf),
-                TransportStream::TLSRaw(ref mut tls) => tls.get_mut().get_mut().write(buf),
+                TransportStream::TLSRaw(ref mut tls) => inner_mut(tls).write(buf),
             }
         }
 
@@ -182,7 +204,7 @@ pub mod tls {
                 TransportStream::None => unreachable!(),
                 TransportStream::Raw(ref mut raw) => raw.flush(),
                 TransportStream::TLS(ref mut tls) => tls.flush(),
-                TransportStream::TLSRaw(ref mut tls) => tls.get_mut().get_mut().flush(),
+                TransportStream::TLSRaw(ref mut tls) => inner_mut(tls).flush(),
             }
         }
     }
@@ -194,7 +216,7 @@ pub mod tls {
                 TransportStream::None => unreachable!(),
                 TransportStream::Raw(ref mut raw) => raw.read(buf),
                 TransportStream::TLS(ref mut tls) => tls.read(buf),
-                TransportStream::TLSRaw(ref mut tls) => tls.get_mut().get_mut().read(buf),
+                TransportStream::TLSRaw(ref mut tls) => inner_mut(tls).read(buf),
             }
         }
     }
@@ -205,34 +227,330 @@ pub mod tls {
                 TransportStream::None => unreachable!(),
                 TransportStream::Raw(ref mut raw) => raw.shutdown(),
                 TransportStream::TLS(ref mut tls) => tls.shutdown(),
-                TransportStream::TLSRaw(ref mut tls) => tls.get_mut().get_mut().shutdown(),
+                TransportStream::TLSRaw(ref mut tls) => inner_mut(tls).shutdown(),
             }
         }
     }
 
     impl<S: Io> AsyncRead for TransportStream<S> {}
 
-    /// #WARNING: If no hostname is provided, certificate validation is DISABLED
-    pub fn connect_async<I: Io>(stream: I, host: Option<&str>) -> Connect<I> {
-        let disable_verification = host.is_none();
-        let mut builder = native_tls::TlsConnector::builder();
+    /// Check the server certificate against the pinned thumbprint (if any)
+    pub fn verify_thumbprint<S: Io>(stream: &TlsStream<S>, params: &ConnectParams) -> Result<()> {
+        let expected = match params.server_certificate {
+            Some(ref thumbprint) => ::parse_thumbprint(thumbprint)?,
+            None => return Ok(()),
+        };
+        let cert = match peer_certificate(stream)? {
+            Some(cert) => cert,
+            None => return Err(Error::Encryption("the server did not present a certificate".into())),
+        };
+        if Sha256::digest(&cert)[..] != expected[..] {
+            return Err(Error::Encryption(
+                "the server certificate does not match the pinned thumbprint".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The backend using the platform's TLS library (e.g. OpenSSL or SChannel)
+    #[cfg(all(feature = "tls", not(feature = "rustls")))]
+    mod native {
+        extern crate native_tls;
+        extern crate tokio_tls;
+
+        use std::fs;
+        use std::io;
+        use transport::Io;
+        use super::TlsTdsWrapper;
+        pub use self::tokio_tls::{Connect, TlsStream};
+        use super::TDS_8_ALPN;
+        use {ConnectParams, EncryptionLevel, Error, Result, TlsVersion};
+
+        impl From<native_tls::Error> for Error {
+            fn from(e: native_tls::Error) -> Error {
+                let err = format!("{:?}", e);
+                Error::Protocol(err.into())
+            }
+        }
+
+        /// Start the TLS handshake, validating the server certificate as configured in `params`
+        ///
+        /// #WARNING: If `trust_cert` is set (except for strict encryption) or a thumbprint is pinned,
+        /// certificate validation is DISABLED
+        /// (the thumbprint has to be checked using `verify_thumbprint` after the handshake)
+        pub fn connect_async<I: Io>(stream: I, params: &ConnectParams) -> Result<Connect<I>> {
+            let mut builder = native_tls::TlsConnector::builder();
+            builder.min_protocol_version(Some(match params.min_tls_version {
+                TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+                TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+                TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+                TlsVersion::Tls13 => native_tls::Protocol::Tlsv13,
+            }));
+
+            if params.ssl == EncryptionLevel::Strict {
+                builder.request_alpns(&[TDS_8_ALPN]);
+            }
+            if params.trust_cert && params.ssl != EncryptionLevel::Strict {
+                builder.danger_accept_invalid_certs(true)
+                       .danger_accept_invalid_hostnames(true)
+                       .use_sni(false);
+            } else if params.server_certificate.is_some() {
+                builder.danger_accept_invalid_certs(true)
+                       .danger_accept_invalid_hostnames(true);
+            }
+            if let Some(ref ca_file) = params.ca_file {
+                let ca = fs::read(&**ca_file)?;
+                let certs = match native_tls::Certificate::stack_from_pem(&ca) {
+                    Ok(ref certs) if certs.is_empty() => vec![native_tls::Certificate::from_der(&ca)?],
+                    Ok(certs) => certs,
+                    Err(_) => vec![native_tls::Certificate::from_der(&ca)?],
+                };
+                for cert in certs {
+                    builder.add_root_certificate(cert);
+                }
+            }
+
+            let cx = builder.build()?;
+            let connector = tokio_tls::TlsConnector::from(cx);
+            let host = params.host_name_in_certificate.as_ref().unwrap_or(&params.host);
+            Ok(connector.connect(host, stream))
+        }
+
+        /// The stream the TLS stream reads from and writes to, e.g. to stop wrapping the data
+        /// into prelogin packets after the handshake
+        pub fn inner_mut<S>(stream: &mut TlsStream<S>) -> &mut S {
+            stream.get_mut().get_mut()
+        }
+
+        /// The negotiated TLS version, which native-tls does not report
+        pub fn protocol_version<S>(_: &TlsStream<S>) -> Option<TlsVersion> {
+            None
+        }
+
+        /// The DER encoded certificate of the server
+        pub fn peer_certificate<S: Io>(stream: &TlsStream<S>) -> Result<Option<Vec<u8>>> {
+            match stream.get_ref().peer_certificate()? {
+                Some(cert) => Ok(Some(cert.to_der()?)),
+                None => Ok(None),
+            }
+        }
+
+        pub fn channel_bindings<S: Io>(stream: &TlsStream<TlsTdsWrapper<S>>) -> io::Result<Option<Vec<u8>>> {
+            #[allow(unused_mut)]
+            let mut bytes: Option<Vec<u8>> = None;
+            // TODO: not landed and not working properly yet
+            #[cfg(all(windows, feature = "channel_bindings"))]
+            {
+                use self::native_tls::backend::schannel::TlsStreamExt;
+                bytes = Some(stream.get_ref().raw_stream().get_finish()?.to_vec());
+            }
+            let _ = stream;
+            Ok(bytes.map(|x| [b"tls-unique:" as &[u8], &x].concat()))
+        }
+    }
+
+    /// The backend using rustls, which does not depend on any platform library
+    #[cfg(feature = "rustls")]
+    mod rustls_backend {
+        extern crate ring;
+        extern crate tokio_rustls;
+        extern crate webpki_roots;
+
+        use std::fs;
+        use std::io;
+        use std::sync::Arc;
+        use self::ring::digest;
+        use self::tokio_rustls::{webpki, TlsConnector};
+        use self::tokio_rustls::rustls::{Certificate, ClientConfig, ProtocolVersion, RootCertStore,
+                                         ServerCertVerified, ServerCertVerifier, Session, TLSError};
+        pub use self::tokio_rustls::Connect;
+        pub use self::tokio_rustls::client::TlsStream;
+        use transport::Io;
+        use super::{TlsTdsWrapper, TDS_8_ALPN};
+        use {ConnectParams, EncryptionLevel, Error, Result, TlsVersion};
+
+        /// Accepts any certificate, either since the user chose to trust the server
+        /// or since the certificate is checked against the pinned thumbprint after the handshake
+        struct AcceptAnyCertificate;
+
+        impl ServerCertVerifier for AcceptAnyCertificate {
+            fn verify_server_cert(
+                &self,
+                _: &RootCertStore,
+                _: &[Certificate],
+                _: webpki::DNSNameRef,
+                _: &[u8],
+            ) -> ::std::result::Result<ServerCertVerified, TLSError> {
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+
+        /// Start the TLS handshake, validating the server certificate as configured in `params`
+        /// against the Mozilla root certificates (and `ca_file`)
+        ///
+        /// #WARNING: If `trust_cert` is set (except for strict encryption) or a thumbprint is pinned,
+        /// certificate validation is DISABLED
+        /// (the thumbprint has to be checked using `verify_thumbprint` after the handshake)
+        pub fn connect_async<I: Io>(stream: I, params: &ConnectParams) -> Result<Connect<I>> {
+            let mut config = ClientConfig::new();
+            config.versions = match params.min_tls_version {
+                TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+                // rustls does not implement TLS versions older than 1.2
+                _ => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            };
+
+            if params.ssl == EncryptionLevel::Strict {
+                config.alpn_protocols = vec![TDS_8_ALPN.as_bytes().to_vec()];
+            }
+            let trust_cert = params.trust_cert && params.ssl != EncryptionLevel::Strict;
+            let verify = !trust_cert && params.server_certificate.is_none();
+            if verify {
+                config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            } else {
+                config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+            }
+            if let Some(ref ca_file) = params.ca_file {
+                let ca = fs::read(&**ca_file)?;
+                let added = match config.root_store.add_pem_file(&mut &ca[..]) {
+                    Ok((valid, _)) if valid > 0 => Ok(()),
+                    _ => config.root_store.add(&Certificate(ca)),
+                };
+                added.map_err(|err| Error::Encryption(format!("invalid CA certificate: {:?}", err).into()))?;
+            }
+
+            let host = params.host_name_in_certificate.as_ref().unwrap_or(&params.host);
+            let name = match webpki::DNSNameRef::try_from_ascii_str(host) {
+                Ok(name) => name,
+                // rustls cannot validate certificates for IP addresses, but only send no SNI
+                Err(_) if !verify => {
+                    config.enable_sni = false;
+                    webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap()
+                }
+                Err(_) => {
+                    return Err(Error::Encryption(
+                        format!(
+                            "the server certificate cannot be validated for {:?} \
+                             (which is no DNS name), use HostNameInCertificate",
+                            host
+                        ).into(),
+                    ))
+                }
+            };
+            if trust_cert {
+                config.enable_sni = false;
+            }
+            Ok(TlsConnector::from(Arc::new(config)).connect(name, stream))
+        }
+
+        /// The stream the TLS stream reads from and writes to, e.g. to stop wrapping the data
+        /// into prelogin packets after the handshake
+        pub fn inner_mut<S>(stream: &mut TlsStream<S>) -> &mut S {
+            stream.get_mut().0
+        }
+
+        /// The negotiated TLS version
+        pub fn protocol_version<S>(stream: &TlsStream<S>) -> Option<TlsVersion> {
+            match stream.get_ref().1.get_protocol_version()? {
+                ProtocolVersion::TLSv1_0 => Some(TlsVersion::Tls10),
+                ProtocolVersion::TLSv1_1 => Some(TlsVersion::Tls11),
+                ProtocolVersion::TLSv1_2 => Some(TlsVersion::Tls12),
+                ProtocolVersion::TLSv1_3 => Some(TlsVersion::Tls13),
+                _ => None,
+            }
+        }
+
+        /// The DER encoded certificate of the server
+        pub fn peer_certificate<S: Io>(stream: &TlsStream<S>) -> Result<Option<Vec<u8>>> {
+            Ok(stream
+                .get_ref()
+                .1
+                .get_peer_certificates()
+                .and_then(|certs| certs.into_iter().next())
+                .map(|cert| cert.0))
+        }
+
+        /// [RFC 5929] the tls-server-end-point channel bindings, the hash of the server certificate
+        pub fn channel_bindings<S: Io>(stream: &TlsStream<TlsTdsWrapper<S>>) -> io::Result<Option<Vec<u8>>> {
+            let cert = match stream.get_ref().1.get_peer_certificates().and_then(|certs| certs.into_iter().next()) {
+                Some(cert) => cert.0,
+                None => return Ok(None),
+            };
+            let hash = digest::digest(end_point_algorithm(&cert), &cert);
+            Ok(Some([b"tls-server-end-point:" as &[u8], hash.as_ref()].concat()))
+        }
+
+        /// split a DER encoded value into its tag, its contents and the data following it
+        fn der_value(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+            let (&tag, data) = data.split_first()?;
+            let (&len, mut data) = data.split_first()?;
+            let len = if len & 0x80 == 0 {
+                len as usize
+            } else {
+                let bytes = (len & 0x7f) as usize;
+                if bytes > 4 || data.len() < bytes {
+                    return None;
+                }
+                let len = data[..bytes].iter().fold(0, |len, byte| len << 8 | *byte as usize);
+                data = &data[bytes..];
+                len
+            };
+            if data.len() < len {
+                return None;
+            }
+            Some((tag, &data[..len], &data[len..]))
+        }
 
-        if disable_verification {
-            builder.danger_accept_invalid_certs(true)
-                   .danger_accept_invalid_hostnames(true)
-                   .use_sni(false);
+        /// The hash of the signature algorithm of the certificate, but at least SHA-256 (RFC 5929 4.1)
+        fn end_point_algorithm(cert: &[u8]) -> &'static digest::Algorithm {
+            const SHA384: [&[u8]; 2] = [
+                &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c], // sha384WithRSAEncryption
+                &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03],       // ecdsa-with-SHA384
+            ];
+            const SHA512: [&[u8]; 2] = [
+                &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d], // sha512WithRSAEncryption
+                &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04],       // ecdsa-with-SHA512
+            ];
+
+            // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+            let oid = der_value(cert)
+                .and_then(|(_, cert, _)| der_value(cert))
+                .and_then(|(_, _, rest)| der_value(rest))
+                .and_then(|(_, algorithm, _)| der_value(algorithm))
+                .map(|(_, oid, _)| oid);
+            match oid {
+                Some(oid) if SHA384.contains(&oid) => &digest::SHA384,
+                Some(oid) if SHA512.contains(&oid) => &digest::SHA512,
+                _ => &digest::SHA256,
+            }
         }
 
-        let cx = builder.build().unwrap();
-        let connector = tokio_tls::TlsConnector::from(cx);
-        connector.connect(host.unwrap_or(""), stream)
+        #[cfg(test)]
+        mod tests {
+            use super::end_point_algorithm;
+
+            #[test]
+            fn end_point_algorithms() {
+                // SEQUENCE { tbsCertificate (long form length), signatureAlgorithm, signatureValue }
+                let mut cert = vec![0x30, 0x81, 0x91, 0x30, 0x81, 0x80];
+                cert.extend(&[0; 0x80]);
+                cert.extend(&[0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03]);
+                cert.extend(&[0x03, 0x00]);
+                assert_eq!(end_point_algorithm(&cert).output_len, 48);
+
+                // other algorithms and truncated certificates use SHA-256
+                let last = cert.len() - 3;
+                cert[last] = 0x02;
+                assert_eq!(end_point_algorithm(&cert).output_len, 32);
+                assert_eq!(end_point_algorithm(&cert[..20]).output_len, 32);
+            }
+        }
     }
 }
 
-#[cfg(feature = "tls")]
+#[cfg(any(feature = "tls", feature = "rustls"))]
 pub use self::tls::*;
 
-#[cfg(not(feature = "tls"))]
+#[cfg(not(any(feature = "tls", feature = "rustls")))]
 pub type TransportStream<S> = S;
 
 #[derive(Debug)]
@@ -250,9 +568,93 @@ pub struct TdsTransport<I: Io> {
     /// if this is false, backtracking (resetting rd.position to 0)
     pub state_tracked: bool,
     pub transaction: u64,
+    /// the server and port the login was routed to
+    pub routing: Option<(String, u16)>,
+    /// the failover partner the server announced
+    pub failover_partner: Option<String>,
+    /// the current state of the session
+    pub session: SessionState,
+    /// the state of the session after the login, the base for recovering it (if the server supports that)
+    pub initial_session: Option<SessionState>,
+    /// the ids of the states the server announced as not recoverable
+    pub unrecoverable: BTreeSet<u8>,
+    /// the cancellation state shared with the handles given out for this connection
+    pub cancel: Arc<CancelState>,
+    /// whether a request was sent whose response was not read completely yet
+    pub request_pending: bool,
+    /// whether we sent an attention signal and wait for it to be acknowledged
+    attention_sent: bool,
+    /// fires when the response to the pending request took too long
+    pub deadline: Option<Delay>,
+    /// whether the pending request was canceled since its deadline elapsed
+    timed_out: bool,
     reinject_token: Option<TdsResponseToken>,
 }
 
+/// Whether the running request should be canceled, the task reading its response is woken up
+/// to send the attention signal
+#[derive(Default)]
+pub struct CancelState {
+    requested: AtomicBool,
+    task: AtomicTask,
+}
+
+impl CancelState {
+    pub fn cancel(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.task.notify();
+    }
+}
+
+/// The state of a session which is restored when recovering it using a new connection
+#[derive(Debug, Clone, Default)]
+pub struct SessionState {
+    pub database: String,
+    pub collation: Bytes,
+    pub language: String,
+    /// the states (e.g. SET options) by their id
+    pub states: BTreeMap<u8, Bytes>,
+}
+
+impl SessionState {
+    /// [2.2.6.4] write the SessionRecoveryData, which only contains the values changed compared to `initial`
+    pub fn write_recovery_data(&self, initial: Option<&SessionState>, buf: &mut Vec<u8>) -> io::Result<()> {
+        let mut data = vec![];
+        let database = match initial {
+            Some(initial) if initial.database == self.database => "",
+            _ => &self.database,
+        };
+        data.write_u8(database.encode_utf16().count() as u8)?;
+        data.write_varchar::<NoLength>(database)?;
+        match initial {
+            Some(initial) if initial.collation == self.collation => data.write_u8(0)?,
+            _ => {
+                data.write_u8(self.collation.len() as u8)?;
+                data.write_all(&self.collation)?;
+            }
+        }
+        let language = match initial {
+            Some(initial) if initial.language == self.language => "",
+            _ => &self.language,
+        };
+        data.write_u8(language.encode_utf16().count() as u8)?;
+        data.write_varchar::<NoLength>(language)?;
+        for (id, value) in &self.states {
+            data.write_u8(*id)?;
+            if value.len() < 0xff {
+                data.write_u8(value.len() as u8)?;
+            } else {
+                data.write_u8(0xff)?;
+                data.write_u32::<LittleEndian>(value.len() as u32)?;
+            }
+            data.write_all(value)?;
+        }
+        buf.write_u32::<LittleEndian>(data.len() as u32)?;
+        buf.extend(data);
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct TdsPacketId(u8);
 
@@ -279,6 +681,7 @@ pub struct TdsTransportInner<I: Io> {
     pub packet_size: usize,
     pub last_meta: Option<Arc<TokenColMetaData>>,
     pub row_bitmap: Option<Bytes>,
+    pub server_info: ServerInfo,
 }
 
 impl<I: Io> Deref for TdsTransportInner<I> {
@@ -353,10 +756,21 @@ impl<I: Io> TdsTransport<I> {
                 packet_size: packet_size,
                 last_meta: None,
                 row_bitmap: None,
+                server_info: ServerInfo::new(packet_size as u32),
             },
             read_state: None,
             state_tracked: false,
             transaction: 0,
+            routing: None,
+            failover_partner: None,
+            session: SessionState::default(),
+            initial_session: None,
+            unrecoverable: BTreeSet::new(),
+            cancel: Arc::new(CancelState::default()),
+            request_pending: false,
+            attention_sent: false,
+            deadline: None,
+            timed_out: false,
             reinject_token: None,
         }
     }
@@ -367,12 +781,44 @@ impl<I: Io> TdsTransport<I> {
         self.inner.next_id()
     }
 
+    /// mark that a request is sent, a cancellation requested before only affected the previous one
+    pub fn start_request(&mut self) {
+        self.cancel.requested.store(false, Ordering::SeqCst);
+        self.request_pending = true;
+        self.deadline = None;
+        self.timed_out = false;
+    }
+
+    /// whether the last request was canceled since its deadline elapsed, resets the flag
+    pub fn take_timed_out(&mut self) -> bool {
+        mem::replace(&mut self.timed_out, false)
+    }
+
+    /// queue the attention signal, the server acknowledges it with a DONE token with the attention bit
+    fn send_attention(&mut self) -> io::Result<()> {
+        let header = PacketHeader {
+            ty: PacketType::AttentionSignal,
+            status: PacketStatus::EndOfMessage,
+            ..PacketHeader::new(protocol::HEADER_BYTES, self.next_id())
+        };
+        let mut buf = vec![0; protocol::HEADER_BYTES];
+        header.serialize(&mut buf)?;
+        self.inner.queue_vec(buf);
+        self.attention_sent = true;
+        Ok(())
+    }
+
     /// reinject a token, so it's returned again on the next call to read_token
     pub fn reinject(&mut self, tok: TdsResponseToken) {
         assert!(self.reinject_token.is_none());
         self.reinject_token = Some(tok);
     }
 
+    /// whether a reinjected token is waiting to be returned by the next call to read_token
+    pub fn has_reinjected(&self) -> bool {
+        self.reinject_token.is_some()
+    }
+
     #[inline]
     pub fn commit_read_state<S: Into<Option<ReadState>>>(&mut self, state: S) {
         self.inner.commit_rd_buffer();
@@ -404,6 +850,10 @@ impl<I: Io> TdsTransport<I> {
                         token,
                         Some(self.inner.read_u16::<LittleEndian>()? as usize),
                     ),
+                    Tokens::FedAuthInfo | Tokens::SessionState => ReadState::Generic(
+                        token,
+                        Some(self.inner.read_u32::<LittleEndian>()? as usize),
+                    ),
                     Tokens::Row | Tokens::NbcRow => {
                         let len = self.inner
                             .last_meta
@@ -436,7 +886,27 @@ impl<I: Io> TdsTransport<I> {
             return Ok(Async::Ready(Some(next_token)));
         }
 
+        // a cancellation requested while no request is running has nothing to cancel
+        self.cancel.task.register();
+        if self.cancel.requested.swap(false, Ordering::SeqCst) && self.request_pending && !self.attention_sent {
+            self.send_attention()?;
+        }
+        let expired = match self.deadline {
+            Some(ref mut deadline) => deadline.poll()?.is_ready(),
+            None => false,
+        };
+        if expired {
+            self.deadline = None;
+            if self.request_pending && !self.attention_sent {
+                self.send_attention()?;
+                self.timed_out = true;
+            }
+        }
+
         loop {
+            if self.attention_sent {
+                self.inner.poll_complete()?;
+            }
             self.inner.commit_rd_buffer();
 
             let ret = match self.read_token() {
@@ -448,7 +918,12 @@ impl<I: Io> TdsTransport<I> {
 
             match ret {
                 Async::NotReady if !self.inner.packets_left && self.inner.len() == 0 => {
-                    return Ok(Async::Ready(None))
+                    if !self.attention_sent {
+                        return Ok(Async::Ready(None));
+                    }
+                    // the acknowledgement of the attention signal may follow in a message of its own
+                    self.inner.packets_left = true;
+                    self.state_tracked = false;
                 }
                 Async::NotReady => {
                     // reset to the last read state
@@ -464,27 +939,101 @@ impl<I: Io> TdsTransport<I> {
                     match ret {
                         TdsResponseToken::EnvChange(env_change) => {
                             match env_change {
+                                TokenEnvChange::Database(new_value, _) => {
+                                    self.session.database = new_value.clone();
+                                    self.inner.server_info.database = new_value;
+                                }
+                                TokenEnvChange::Language(new_value, _) => {
+                                    self.session.language = new_value;
+                                }
+                                TokenEnvChange::SqlCollation(new_value, _) => {
+                                    self.inner.server_info.collation = Collation::from_bytes(&new_value);
+                                    self.session.collation = new_value;
+                                }
                                 TokenEnvChange::PacketSize(new_size, _) => {
                                     self.inner.packet_size = new_size as usize;
+                                    self.inner.server_info.packet_size = new_size;
                                 }
                                 TokenEnvChange::BeginTransaction(trans_id) => {
                                     self.transaction = trans_id;
                                 }
                                 TokenEnvChange::RollbackTransaction(old_trans_id) |
                                 TokenEnvChange::CommitTransaction(old_trans_id) => {
-                                    assert_eq!(self.transaction, old_trans_id);
+                                    if self.transaction != old_trans_id {
+                                        return Err(Error::Protocol(
+                                            format!("envchange: end of unknown transaction {:x}", old_trans_id).into(),
+                                        ));
+                                    }
                                     self.transaction = 0;
                                 }
+                                TokenEnvChange::TransactionEnded(_) => {
+                                    self.transaction = 0;
+                                }
+                                TokenEnvChange::MirroringPartner(partner) => {
+                                    self.failover_partner = Some(partner);
+                                }
+                                TokenEnvChange::Routing(server, port) => {
+                                    self.routing = Some((server, port));
+                                }
                                 _ => (),
                             }
                             continue;
                         }
+                        TdsResponseToken::LoginAck(ref ack) => {
+                            let info = &mut self.inner.server_info;
+                            info.program_name = ack.prog_name.as_str().to_owned();
+                            info.feature_level = ack.tds_version;
+                            info.version = ServerVersion::from_login_ack(ack.version);
+                        }
+                        TdsResponseToken::FeatureExtAck(ref ack) => {
+                            let features = &mut self.inner.server_info.features;
+                            for feature in &ack.features {
+                                match *feature {
+                                    FeatureAck::SessionRecovery(ref states) => {
+                                        features.session_recovery = true;
+                                        self.session.states = states.iter().cloned().collect();
+                                    }
+                                    FeatureAck::FedAuth(_) => features.fed_auth = true,
+                                    FeatureAck::AzureSqlSupport(_) => features.azure_sql_support = true,
+                                    FeatureAck::Utf8Support(enabled) => features.utf8 = enabled,
+                                    FeatureAck::Unknown(..) => (),
+                                }
+                            }
+                        }
+                        TdsResponseToken::SessionState(state) => {
+                            for (id, value) in state.states {
+                                if state.recoverable {
+                                    self.unrecoverable.remove(&id);
+                                } else {
+                                    self.unrecoverable.insert(id);
+                                }
+                                self.session.states.insert(id, value);
+                            }
+                            continue;
+                        }
                         TdsResponseToken::Info(_) | TdsResponseToken::Order(_) => continue,
+                        TdsResponseToken::Error(_) if self.attention_sent => continue,
                         TdsResponseToken::Error(err) => {
                             return Err(Error::Server(err));
                         }
+                        TdsResponseToken::Done(ref done) | TdsResponseToken::DoneProc(ref done)
+                            if !done.status.contains(DoneStatus::MORE) =>
+                        {
+                            self.request_pending = false;
+                            self.deadline = None;
+                        }
                         _ => (),
                     }
+                    // skip the rest of the canceled response up to the acknowledgement of the attention,
+                    // which ends the response for the consumer
+                    if self.attention_sent {
+                        match ret {
+                            TdsResponseToken::Done(ref done) if done.status.contains(DoneStatus::ATTENTION) => {
+                                self.attention_sent = false;
+                            }
+                            _ => continue,
+                        }
+                    }
                     return Ok(Async::Ready(Some(ret)));
                 }
             }
@@ -609,6 +1158,43 @@ impl<I: Io> TdsTransportInner<I> {
         self.read_bytes(len).unwrap()
     }
 
+    /// Whether data of a response was received, which is not consumed yet
+    pub fn receiving(&self) -> bool {
+        self.header.is_some() || self.missing != protocol::HEADER_BYTES
+            || (self.rd.position() as usize) < self.rd.get_ref().len()
+    }
+
+    /// Whether the server closed the connection (or it broke) while no response was expected,
+    /// which is noticed by trying to read. Data which arrived nevertheless is kept as the start of a packet.
+    pub fn closed_while_idle(&mut self) -> bool {
+        if self.receiving() {
+            return false;
+        }
+        match self.io.poll_read(&mut self.hrd) {
+            Ok(Async::Ready(0)) | Err(_) => true,
+            Ok(Async::Ready(amount)) => {
+                self.missing -= amount;
+                false
+            }
+            Ok(Async::NotReady) => false,
+        }
+    }
+
+    /// whether packets are waiting to be sent
+    pub fn has_queued(&self) -> bool {
+        !self.wr.is_empty()
+    }
+
+    /// a copy of the packets waiting to be sent
+    pub fn queued_packets(&self) -> Vec<Vec<u8>> {
+        self.wr.iter().map(|&(pos, ref packet)| packet[pos..].to_vec()).collect()
+    }
+
+    /// queue packets to send (e.g. a request to send again)
+    pub fn queue_packets(&mut self, packets: Vec<Vec<u8>>) {
+        self.wr.extend(packets.into_iter().map(|packet| (0, packet)));
+    }
+
     /// buffers another packet from the underlying IO (or continues the last I/O operation)
     pub fn next_packet(&mut self) -> Poll<PacketHeader, Error> {
         // read the header first
@@ -630,6 +1216,7 @@ impl<I: Io> TdsTransportInner<I> {
             }
 
             let header = PacketHeader::unserialize(&self.hrd)?;
+            self.server_info.spid = header.spid;
             self.missing = header.length as usize - protocol::HEADER_BYTES;
             self.header = Some(header);
         }
@@ -640,14 +1227,16 @@ impl<I: Io> TdsTransportInner<I> {
             while self.missing > 0 {
                 let buf = mem::replace(self.rd.get_mut(), Bytes::new());
                 let mut write_buf = match buf.try_mut() {
+                    // reserve space for a whole packet of the negotiated size at once
                     Ok(mut buf) => {
                         if buf.remaining_mut() < self.missing {
-                            buf.reserve(self.missing);
+                            buf.reserve(cmp::max(self.missing, self.packet_size));
                         }
                         buf
                     }
                     Err(old_buf) => {
-                        let mut buf = BytesMut::with_capacity(old_buf.len() + self.missing);
+                        let capacity = old_buf.len() + cmp::max(self.missing, self.packet_size);
+                        let mut buf = BytesMut::with_capacity(capacity);
                         buf.put_slice(old_buf.as_ref());
                         buf
                     }
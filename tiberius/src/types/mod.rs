@@ -134,7 +134,7 @@ uint_enum! {
 
 const MAX_NVARCHAR_SIZE: usize = 1 << 30;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Collation {
     /// LCID ColFlags Version
     info: u32,
@@ -143,14 +143,32 @@ pub struct Collation {
 }
 
 impl Collation {
+    /// parse a collation as sent in an ENVCHANGE, which is empty for none
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Collation> {
+        if bytes.len() != 5 {
+            return None;
+        }
+        Some(Collation {
+            info: LittleEndian::read_u32(bytes),
+            sort_id: bytes[4],
+        })
+    }
+
     /// return the locale id part of the LCID (the specification here uses ambiguous terms)
     pub fn lcid(&self) -> u16 {
         (self.info & 0xffff) as u16
     }
 
+    /// whether character data is encoded using UTF-8 (fUTF8, only sent if the client supports it)
+    pub fn is_utf8(&self) -> bool {
+        self.info & 0x0400_0000 != 0
+    }
+
     /// return an encoding for a given collation
     pub fn encoding(&self) -> Option<&'static Encoding> {
-        if self.sort_id == 0 {
+        if self.is_utf8() {
+            Some(encoding::all::UTF_8)
+        } else if self.sort_id == 0 {
             collation::lcid_to_encoding(self.lcid())
         } else {
             collation::sortid_to_encoding(self.sort_id)